@@ -1,3 +1,17 @@
 fn main() {
+    // the app version is kept in package.json (see tauri.conf.json), the user
+    // agent includes it
+    println!("cargo:rerun-if-changed=../package.json");
+    let package: serde_json::Value = serde_json::from_str(
+        &std::fs::read_to_string("../package.json").expect("Error reading package.json"),
+    )
+    .expect("Error parsing package.json");
+    println!(
+        "cargo:rustc-env=APP_VERSION={}",
+        package["version"]
+            .as_str()
+            .expect("No version in package.json")
+    );
+
     tauri_build::build()
 }
@@ -0,0 +1 @@
+drop table if exists analysis_history;
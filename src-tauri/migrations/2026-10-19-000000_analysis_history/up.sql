@@ -0,0 +1,13 @@
+create table analysis_history (
+  id text primary key not null,
+  session_id text not null,
+  engine_id text not null,
+  engine_name text not null,
+  variant text not null,
+  initial_fen text not null,
+  moves text not null,
+  lines text not null,
+  created_at timestamp not null default current_timestamp
+);
+
+create index analysis_history_session_id on analysis_history (session_id);
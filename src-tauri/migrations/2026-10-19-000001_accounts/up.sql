@@ -0,0 +1,11 @@
+-- Accounts besides the one configured in settings.
+-- Their token and provider secret are kept in secret storage, not here.
+create table accounts (
+  id integer primary key autoincrement not null,
+  lichess_host text not null,
+  engine_host text not null,
+  username text not null,
+  token_expires_at bigint,
+  enabled boolean not null default 1,
+  unique (lichess_host, username)
+);
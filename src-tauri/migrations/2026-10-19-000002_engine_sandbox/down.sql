@@ -0,0 +1,5 @@
+alter table engines drop column nice;
+alter table engines drop column allowed_paths;
+alter table engines drop column cpu_time_limit_secs;
+alter table engines drop column memory_limit_mb;
+alter table engines drop column sandbox;
@@ -0,0 +1,7 @@
+-- How each engine process is started, see sandbox.rs
+alter table engines add column sandbox boolean not null default 0;
+alter table engines add column memory_limit_mb integer;
+alter table engines add column cpu_time_limit_secs integer;
+-- newline separated, e.g. tablebase directories
+alter table engines add column allowed_paths text not null default '';
+alter table engines add column nice integer;
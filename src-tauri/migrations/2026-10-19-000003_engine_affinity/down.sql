@@ -0,0 +1,2 @@
+alter table engines drop column free_cores;
+alter table engines drop column cpus;
@@ -0,0 +1,4 @@
+-- Cores the engine runs on, like 0-3,6. Unset uses the engine_cpus setting.
+alter table engines add column cpus text;
+-- Cores left to other programs. Unset uses the engine_free_cores setting.
+alter table engines add column free_cores integer;
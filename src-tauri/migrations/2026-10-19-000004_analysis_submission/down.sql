@@ -0,0 +1 @@
+alter table analysis_history drop column submission_error;
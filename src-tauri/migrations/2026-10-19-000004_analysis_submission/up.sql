@@ -0,0 +1,3 @@
+-- Why Lichess didn't take the analysis (an error status, a timeout, a dropped
+-- connection). Unset when it was sent.
+alter table analysis_history add column submission_error text;
@@ -0,0 +1,236 @@
+//! Lichess accounts the app provides analysis to.
+//!
+//! The account configured in settings (the one logged in with OAuth) is the
+//! default account, with id [`DEFAULT_ACCOUNT`]. Further accounts, possibly on
+//! other Lichess instances, are rows of the `accounts` table. Like the default
+//! account's, their token and provider secret are kept in [`secrets`].
+
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::{
+    db::{self, NewAccount},
+    events::EventSink,
+    lichess::{send_status_to_frontend, StatusLevel, StatusPayload},
+    lichess_api::{ApiError, LichessApi},
+    login, secrets,
+    settings::{self, parse_url},
+};
+
+pub const DEFAULT_ACCOUNT: i32 = 0;
+
+#[derive(Clone, Debug, Serialize)]
+pub struct Account {
+    pub id: i32,
+    pub lichess_host: String,
+    pub engine_host: String,
+    pub username: Option<String>,
+    #[serde(skip_serializing)]
+    pub lichess_token: Option<String>,
+    #[serde(skip_serializing)]
+    pub provider_secret: String,
+    pub token_expires_at: Option<i64>,
+    pub enabled: bool,
+}
+
+fn token_key(id: i32) -> String {
+    format!("account_{}_lichess_token", id)
+}
+
+fn provider_secret_key(id: i32) -> String {
+    format!("account_{}_provider_secret", id)
+}
+
+fn read_secret(key: &str) -> Option<String> {
+    secrets::store().get(key).unwrap_or_else(|e| {
+        error!("Error reading {} from secret storage: {}", key, e);
+        None
+    })
+}
+
+fn default_account() -> Account {
+    let settings = settings::get();
+
+    Account {
+        id: DEFAULT_ACCOUNT,
+        lichess_host: settings.lichess_host,
+        engine_host: settings.engine_host,
+        username: settings.lichess_username,
+        lichess_token: settings.lichess_token,
+        provider_secret: settings.provider_secret,
+        token_expires_at: settings.lichess_token_expires_at,
+        enabled: settings.provider_enabled,
+    }
+}
+
+impl From<db::SqlAccount> for Account {
+    fn from(account: db::SqlAccount) -> Self {
+        Account {
+            id: account.id,
+            lichess_token: read_secret(&token_key(account.id)),
+            provider_secret: read_secret(&provider_secret_key(account.id)).unwrap_or_default(),
+            lichess_host: account.lichess_host,
+            engine_host: account.engine_host,
+            username: Some(account.username),
+            token_expires_at: account.token_expires_at,
+            enabled: account.enabled,
+        }
+    }
+}
+
+pub fn get(id: i32) -> Option<Account> {
+    match id {
+        DEFAULT_ACCOUNT => Some(default_account()),
+        _ => db::get_account(id).map(Account::from),
+    }
+}
+
+pub fn all() -> Vec<Account> {
+    let mut accounts = vec![default_account()];
+    accounts.extend(db::get_all_accounts().into_iter().map(Account::from));
+    accounts
+}
+
+/// Adds an account using a personal API token with the engine scopes. Adding
+/// an account again replaces its token, keeping its provider secret so its
+/// engines keep working.
+pub fn add(lichess_host: &str, engine_host: &str, token: &str) -> Result<Account, String> {
+    let lichess_host = parse_url("lichess_host", lichess_host).map_err(|e| e.to_string())?;
+    let engine_host = parse_url("engine_host", engine_host).map_err(|e| e.to_string())?;
+    let token = token.trim();
+
+    let token_expires_at = login::verify_token(&lichess_host, token)?;
+    let username = login::fetch_username(&lichess_host, token)
+        .map_err(|e| format!("Error fetching the Lichess account: {}", e))?;
+
+    let existing = all().into_iter().find(|account| {
+        account.lichess_host == lichess_host && account.username.as_ref() == Some(&username)
+    });
+    match existing {
+        Some(account) if account.id == DEFAULT_ACCOUNT => {
+            return Err(format!(
+                "{} on {} is the account you're logged in with",
+                username, lichess_host
+            ));
+        }
+        Some(account) => {
+            secrets::store()
+                .set(&token_key(account.id), token)
+                .map_err(|e| format!("Error saving the account credentials: {}", e))?;
+            db::update_account(account.id, &engine_host, token_expires_at);
+            return get(account.id).ok_or_else(|| "Error loading the account".to_string());
+        }
+        None => {}
+    }
+
+    let id = db::add_account(&NewAccount {
+        lichess_host: &lichess_host,
+        engine_host: &engine_host,
+        username: &username,
+        token_expires_at,
+    });
+
+    let saved = secrets::store()
+        .set(&token_key(id), token)
+        .and_then(|_| secrets::store().set(&provider_secret_key(id), &settings::generate_secret()));
+    if let Err(e) = saved {
+        remove(id)?;
+        return Err(format!("Error saving the account credentials: {}", e));
+    }
+
+    get(id).ok_or_else(|| "Error loading the new account".to_string())
+}
+
+pub fn remove(id: i32) -> Result<(), String> {
+    if id == DEFAULT_ACCOUNT {
+        return Err("The default account can't be removed, log out instead".to_string());
+    }
+
+    db::delete_account(id);
+
+    for key in [token_key(id), provider_secret_key(id)] {
+        if let Err(e) = secrets::store().delete(&key) {
+            warn!("Error deleting {}: {}", key, e);
+        }
+    }
+
+    Ok(())
+}
+
+pub fn set_enabled(id: i32, enabled: bool) -> Result<(), String> {
+    match id {
+        DEFAULT_ACCOUNT => {
+            settings::update("provider_enabled", &enabled.to_string()).map_err(|e| e.to_string())
+        }
+        _ if db::get_account(id).is_none() => Err(format!("No account with id {}", id)),
+        _ => {
+            db::set_account_enabled(id, enabled);
+            Ok(())
+        }
+    }
+}
+
+fn set_provider_secret(id: i32, provider_secret: &str) -> Result<(), String> {
+    match id {
+        DEFAULT_ACCOUNT => {
+            settings::update("provider_secret", provider_secret).map_err(|e| e.to_string())
+        }
+        _ => secrets::store()
+            .set(&provider_secret_key(id), provider_secret)
+            .map_err(|e| e.to_string()),
+    }
+}
+
+/// Replaces the provider secret of an account, for when it may have leaked.
+///
+/// Every engine registered on the account is updated to the new secret
+/// first, and the worker only switches once all of them are. If anything
+/// fails, the engines already updated are put back on the old secret.
+pub fn rotate_provider_secret(account: &Account, api: &LichessApi) -> Result<(), ApiError> {
+    let new_secret = settings::generate_secret();
+    let mut updated = Vec::new();
+
+    let result = api.list_engines().and_then(|engines| {
+        for engine in engines {
+            api.update_engine(&engine.id, &engine.registration(&new_secret))?;
+            updated.push(engine);
+        }
+
+        set_provider_secret(account.id, &new_secret).map_err(ApiError::Storage)
+    });
+
+    if result.is_err() {
+        for engine in updated {
+            let registration = engine.registration(&account.provider_secret);
+            if let Err(e) = api.update_engine(&engine.id, &registration) {
+                error!(
+                    "Error restoring the provider secret of {}: {}",
+                    engine.id, e
+                );
+            }
+        }
+    }
+
+    result
+}
+
+/// Forgets a token Lichess no longer accepts, so the account stops polling
+/// until it's logged in (or added) again
+pub fn expire_token(sink: &dyn EventSink, id: i32, reason: &str) {
+    if id == DEFAULT_ACCOUNT {
+        login::expire_token(sink, reason);
+        return;
+    }
+
+    if let Err(e) = secrets::store().delete(&token_key(id)) {
+        warn!("Error deleting {}: {}", token_key(id), e);
+    }
+
+    send_status_to_frontend(
+        sink,
+        StatusPayload {
+            status: reason.to_string(),
+            level: StatusLevel::Error,
+        },
+    );
+}
@@ -0,0 +1,92 @@
+//! Exponential backoff with jitter, for retrying requests to Lichess.
+
+use std::time::Duration;
+
+use rand::Rng;
+
+const INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Doubles the delay after each failure, up to a cap, and picks a random
+/// delay between half and all of it. Providers that lost the connection at
+/// the same time (a Lichess restart, a network outage) then don't all come
+/// back at once.
+pub struct Backoff {
+    failures: u32,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff { failures: 0 }
+    }
+
+    /// Call after a successful request, so the next failure starts over
+    pub fn reset(&mut self) {
+        self.failures = 0;
+    }
+
+    /// How long to wait before retrying after another failure
+    pub fn next_delay(&mut self, cap: Duration) -> Duration {
+        let delay = INITIAL_DELAY
+            .checked_mul(1 << self.failures.min(16))
+            .map_or(cap, |delay| delay.min(cap));
+        self.failures = self.failures.saturating_add(1);
+
+        delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_between(delay: Duration, min: Duration, max: Duration) {
+        assert!(
+            min <= delay && delay <= max,
+            "{:?} not between {:?} and {:?}",
+            delay,
+            min,
+            max
+        );
+    }
+
+    #[test]
+    fn doubles_up_to_the_cap() {
+        let cap = Duration::from_secs(10);
+        let mut backoff = Backoff::new();
+
+        for full in [1, 2, 4, 8, 10, 10, 10] {
+            let full = Duration::from_secs(full);
+            assert_between(backoff.next_delay(cap), full / 2, full);
+        }
+
+        // past the largest shift, the cap still applies
+        for _ in 0..40 {
+            assert_between(backoff.next_delay(cap), cap / 2, cap);
+        }
+    }
+
+    #[test]
+    fn starts_over_after_a_reset() {
+        let mut backoff = Backoff::new();
+        for _ in 0..5 {
+            backoff.next_delay(Duration::from_secs(60));
+        }
+
+        backoff.reset();
+        assert_between(
+            backoff.next_delay(Duration::from_secs(60)),
+            INITIAL_DELAY / 2,
+            INITIAL_DELAY,
+        );
+    }
+
+    #[test]
+    fn jitters() {
+        let mut delays: Vec<Duration> = (0..20)
+            .map(|_| Backoff::new().next_delay(Duration::from_secs(60)))
+            .collect();
+        delays.dedup();
+
+        assert!(delays.len() > 1);
+    }
+}
@@ -0,0 +1,52 @@
+//! Tells whether the network is up, so the worker can wait for it to come
+//! back instead of backing off while a laptop is off Wi-Fi.
+
+use std::{
+    net::TcpStream,
+    sync::mpsc::{Receiver, RecvTimeoutError},
+    thread,
+    time::Duration,
+};
+
+use reqwest::Url;
+
+use crate::settings::{self, Settings};
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+/// How often to check while offline
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Whether the host of `url` resolves and accepts a TCP connection. With a
+/// proxy configured, only the proxy needs to be reachable.
+pub fn is_online(url: &str) -> bool {
+    let proxy = settings::get().proxy;
+    let url = match Url::parse(proxy.as_deref().unwrap_or(url)) {
+        Ok(url) => url,
+        // not for this to judge
+        Err(_) => return true,
+    };
+
+    let addrs = match url.socket_addrs(|| match url.scheme() {
+        "socks5" | "socks5h" => Some(1080),
+        _ => None,
+    }) {
+        Ok(addrs) => addrs,
+        // DNS doesn't resolve
+        Err(_) => return false,
+    };
+
+    addrs
+        .iter()
+        .any(|addr| TcpStream::connect_timeout(addr, CONNECT_TIMEOUT).is_ok())
+}
+
+/// Blocks until `url` can be reached, or a setting changes (e.g. the proxy)
+pub fn wait_until_online(url: &str, changes: &Receiver<Settings>) {
+    while !is_online(url) {
+        match changes.recv_timeout(CHECK_INTERVAL) {
+            Ok(_) => return,
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => thread::sleep(CHECK_INTERVAL),
+        }
+    }
+}
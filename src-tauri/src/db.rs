@@ -1,5 +1,12 @@
 use crate::{schema, utils::get_app_data_dir};
-use diesel::prelude::*;
+use diesel::{
+    connection::SimpleConnection,
+    prelude::*,
+    r2d2::{ConnectionManager, CustomizeConnection, Pool, PooledConnection},
+};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use tracing::{error, info};
 
 #[derive(Queryable)]
 pub struct SqlSetting {
@@ -7,11 +14,73 @@ pub struct SqlSetting {
     pub value: String,
 }
 
-#[allow(dead_code)]
-#[derive(Queryable)]
+#[derive(Queryable, Serialize)]
 pub struct SqlEngine {
-    engine_id: String,
-    binary_location: String,
+    pub engine_id: String,
+    pub binary_location: String,
+    pub sandbox: bool,
+    pub memory_limit_mb: Option<i32>,
+    pub cpu_time_limit_secs: Option<i32>,
+    pub allowed_paths: String,
+    pub nice: Option<i32>,
+    pub cpus: Option<String>,
+    pub free_cores: Option<i32>,
+}
+
+impl SqlEngine {
+    pub fn options(&self) -> EngineOptions {
+        EngineOptions {
+            sandbox: self.sandbox,
+            memory_limit_mb: self.memory_limit_mb,
+            cpu_time_limit_secs: self.cpu_time_limit_secs,
+            allowed_paths: self.allowed_paths.clone(),
+            nice: self.nice,
+            cpus: self.cpus.clone(),
+            free_cores: self.free_cores,
+        }
+    }
+}
+
+/// How the engine process is started, see [`crate::sandbox`]
+#[derive(AsChangeset, Clone, Debug, Default, Serialize, Deserialize)]
+#[diesel(table_name = schema::engines, treat_none_as_null = true)]
+pub struct EngineOptions {
+    /// Restrict filesystem and network access (Linux only)
+    pub sandbox: bool,
+    pub memory_limit_mb: Option<i32>,
+    pub cpu_time_limit_secs: Option<i32>,
+    /// Extra directories the sandboxed engine may read, one per line
+    pub allowed_paths: String,
+    /// Overrides the `engine_nice` setting
+    pub nice: Option<i32>,
+    /// Cores to run on, like `0-3,6`. Overrides the `engine_cpus` setting.
+    pub cpus: Option<String>,
+    /// Overrides the `engine_free_cores` setting
+    pub free_cores: Option<i32>,
+}
+
+#[derive(Queryable)]
+pub struct SqlAnalysis {
+    pub id: String,
+    pub session_id: String,
+    pub engine_id: String,
+    pub engine_name: String,
+    pub variant: String,
+    pub initial_fen: String,
+    pub moves: String,
+    pub lines: String,
+    pub created_at: chrono::NaiveDateTime,
+    pub submission_error: Option<String>,
+}
+
+#[derive(Queryable)]
+pub struct SqlAccount {
+    pub id: i32,
+    pub lichess_host: String,
+    pub engine_host: String,
+    pub username: String,
+    pub token_expires_at: Option<i64>,
+    pub enabled: bool,
 }
 
 #[derive(Insertable)]
@@ -28,15 +97,77 @@ struct NewEngine<'a> {
     binary_location: &'a str,
 }
 
-pub fn establish_connection() -> SqliteConnection {
+#[derive(Insertable)]
+#[diesel(table_name = schema::analysis_history)]
+pub struct NewAnalysis<'a> {
+    pub id: &'a str,
+    pub session_id: &'a str,
+    pub engine_id: &'a str,
+    pub engine_name: &'a str,
+    pub variant: &'a str,
+    pub initial_fen: &'a str,
+    pub moves: &'a str,
+    pub lines: &'a str,
+    pub submission_error: Option<&'a str>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = schema::accounts)]
+pub struct NewAccount<'a> {
+    pub lichess_host: &'a str,
+    pub engine_host: &'a str,
+    pub username: &'a str,
+    pub token_expires_at: Option<i64>,
+}
+
+pub type DbConnection = PooledConnection<ConnectionManager<SqliteConnection>>;
+
+/// Applied to every connection the pool opens.
+/// WAL lets the worker write analysis history while commands read, and the
+/// busy timeout makes concurrent writers wait instead of failing with
+/// `database is locked`.
+#[derive(Debug)]
+struct ConnectionOptions;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for ConnectionOptions {
+    fn on_acquire(&self, connection: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        connection
+            .batch_execute(
+                "PRAGMA journal_mode = WAL;
+                 PRAGMA synchronous = NORMAL;
+                 PRAGMA busy_timeout = 5000;
+                 PRAGMA foreign_keys = ON;",
+            )
+            .map_err(|e| {
+                error!("Error setting up a database connection: {}", e);
+                diesel::r2d2::Error::QueryError(e)
+            })
+    }
+}
+
+static POOL: Lazy<Pool<ConnectionManager<SqliteConnection>>> = Lazy::new(|| {
     let path_to_db_file = get_app_data_dir().join("db.sqlite");
 
     let database_url = path_to_db_file
         .to_str()
         .unwrap_or_else(|| panic!("Error convert path {:?} to url", path_to_db_file));
 
-    SqliteConnection::establish(database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", &database_url))
+    info!("Opening the database at {}", database_url);
+    Pool::builder()
+        .max_size(4)
+        .connection_customizer(Box::new(ConnectionOptions))
+        .build(ConnectionManager::new(database_url))
+        .unwrap_or_else(|e| {
+            error!("Error connecting to {}: {}", database_url, e);
+            panic!("Error connecting to {}", &database_url)
+        })
+});
+
+pub fn establish_connection() -> DbConnection {
+    POOL.get().unwrap_or_else(|e| {
+        error!("Error getting a database connection: {}", e);
+        panic!("Error getting a database connection")
+    })
 }
 
 pub fn update_setting(key: &str, value: &str) {
@@ -60,19 +191,6 @@ pub fn delete_setting(key: &str) {
         .expect("Error deleting setting");
 }
 
-pub fn get_setting(key: &str) -> Option<String> {
-    let mut connection = establish_connection();
-
-    let result = schema::settings::table
-        .filter(schema::settings::key.eq(key))
-        .first::<SqlSetting>(&mut connection);
-
-    match result {
-        Ok(setting) => Some(setting.value),
-        Err(_) => None,
-    }
-}
-
 pub fn get_all_settings() -> Vec<SqlSetting> {
     let mut connection = establish_connection();
 
@@ -96,6 +214,25 @@ pub fn add_engine(engine_id: &str, binary_location: &str) {
         .expect("Error saving new engine");
 }
 
+/// Links an engine to a binary, replacing the binary of one already linked
+/// but keeping its options
+pub fn set_engine_binary_location(engine_id: &str, binary_location: &str) {
+    let mut connection = establish_connection();
+
+    let new_engine = NewEngine {
+        engine_id,
+        binary_location,
+    };
+
+    diesel::insert_into(schema::engines::table)
+        .values(&new_engine)
+        .on_conflict(schema::engines::engine_id)
+        .do_update()
+        .set(schema::engines::binary_location.eq(binary_location))
+        .execute(&mut connection)
+        .expect("Error saving engine binary location");
+}
+
 pub fn delete_engine(engine_id: &str) {
     let mut connection = establish_connection();
 
@@ -104,6 +241,25 @@ pub fn delete_engine(engine_id: &str) {
         .expect("Error deleting engine");
 }
 
+pub fn update_engine_options(engine_id: &str, options: &EngineOptions) {
+    let mut connection = establish_connection();
+
+    diesel::update(schema::engines::table.find(engine_id))
+        .set(options)
+        .execute(&mut connection)
+        .expect("Error saving engine options");
+}
+
+pub fn get_engine(engine_id: &str) -> Option<SqlEngine> {
+    let mut connection = establish_connection();
+
+    schema::engines::table
+        .find(engine_id)
+        .first::<SqlEngine>(&mut connection)
+        .optional()
+        .expect("Error loading engine")
+}
+
 pub fn get_engine_binary_path(engine_id: &str) -> Option<String> {
     let mut connection = establish_connection();
 
@@ -133,3 +289,114 @@ pub fn get_engine_count() -> i64 {
         .get_result(&mut connection)
         .expect("Error getting engine count")
 }
+
+pub fn add_analysis(analysis: &NewAnalysis) {
+    let mut connection = establish_connection();
+
+    diesel::insert_into(schema::analysis_history::table)
+        .values(analysis)
+        .on_conflict(schema::analysis_history::id)
+        .do_update()
+        .set((
+            schema::analysis_history::lines.eq(analysis.lines),
+            schema::analysis_history::submission_error.eq(analysis.submission_error),
+        ))
+        .execute(&mut connection)
+        .expect("Error saving analysis");
+}
+
+pub fn get_session_analyses(session_id: &str) -> Vec<SqlAnalysis> {
+    let mut connection = establish_connection();
+
+    schema::analysis_history::table
+        .filter(schema::analysis_history::session_id.eq(session_id))
+        .order(schema::analysis_history::created_at.asc())
+        .load::<SqlAnalysis>(&mut connection)
+        .expect("Error loading analysis history")
+}
+
+pub fn get_analysis_sessions() -> Vec<(String, chrono::NaiveDateTime, i64)> {
+    let mut connection = establish_connection();
+
+    schema::analysis_history::table
+        .group_by(schema::analysis_history::session_id)
+        .select((
+            schema::analysis_history::session_id,
+            diesel::dsl::max(schema::analysis_history::created_at),
+            diesel::dsl::count_star(),
+        ))
+        .order(diesel::dsl::max(schema::analysis_history::created_at).desc())
+        .load::<(String, Option<chrono::NaiveDateTime>, i64)>(&mut connection)
+        .expect("Error loading analysis sessions")
+        .into_iter()
+        .filter_map(|(session_id, last_analyzed_at, count)| {
+            Some((session_id, last_analyzed_at?, count))
+        })
+        .collect()
+}
+
+/// Inserts the account and returns its id
+pub fn add_account(account: &NewAccount) -> i32 {
+    let mut connection = establish_connection();
+
+    connection
+        .transaction(|connection| {
+            diesel::insert_into(schema::accounts::table)
+                .values(account)
+                .execute(connection)?;
+
+            schema::accounts::table
+                .select(schema::accounts::id)
+                .order(schema::accounts::id.desc())
+                .first::<i32>(connection)
+        })
+        .expect("Error saving new account")
+}
+
+pub fn delete_account(id: i32) {
+    let mut connection = establish_connection();
+
+    diesel::delete(schema::accounts::table.find(id))
+        .execute(&mut connection)
+        .expect("Error deleting account");
+}
+
+pub fn set_account_enabled(id: i32, enabled: bool) {
+    let mut connection = establish_connection();
+
+    diesel::update(schema::accounts::table.find(id))
+        .set(schema::accounts::enabled.eq(enabled))
+        .execute(&mut connection)
+        .expect("Error updating account");
+}
+
+pub fn update_account(id: i32, engine_host: &str, token_expires_at: Option<i64>) {
+    let mut connection = establish_connection();
+
+    diesel::update(schema::accounts::table.find(id))
+        .set((
+            schema::accounts::engine_host.eq(engine_host),
+            schema::accounts::token_expires_at.eq(token_expires_at),
+        ))
+        .execute(&mut connection)
+        .expect("Error updating account");
+}
+
+pub fn get_account(id: i32) -> Option<SqlAccount> {
+    let mut connection = establish_connection();
+
+    schema::accounts::table
+        .find(id)
+        .first::<SqlAccount>(&mut connection)
+        .optional()
+        .expect("Error loading account")
+}
+
+pub fn get_all_accounts() -> Vec<SqlAccount> {
+    let mut connection = establish_connection();
+
+    schema::accounts::table
+        .order(schema::accounts::id.asc())
+        .load::<SqlAccount>(&mut connection)
+        .expect("Error loading accounts")
+}
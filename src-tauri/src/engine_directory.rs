@@ -1,3 +1,4 @@
+use std::error::Error;
 use std::fs;
 use std::fs::File;
 use std::io;
@@ -6,17 +7,28 @@ use std::path::PathBuf;
 
 use serde::Deserialize;
 use serde::Serialize;
+use serde_json::json;
 use tar::Archive;
+use tracing::info;
 
+use crate::events::EventSink;
+use crate::http;
 use crate::utils::get_app_data_dir;
 
+const ENGINE_DIRECTORY_URL: &str = "https://fitztrev.github.io/lichess-tauri/engine-directory.json";
+
+#[derive(Debug, Serialize, Deserialize)]
+struct EngineDirectory {
+    engines: Vec<Engine>,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Engine {
-    name: String,
+    pub name: String,
     description: String,
     website: String,
     license: String,
-    version: String,
+    pub version: String,
     updated_at: String,
     binaries: Vec<Binary>,
 }
@@ -29,6 +41,21 @@ pub struct Binary {
     binary_filename: String,
 }
 
+/// Looks up an engine by name in the published engine directory
+pub fn find(name: &str) -> Result<Engine, Box<dyn Error>> {
+    let directory = http::client()?
+        .get(ENGINE_DIRECTORY_URL)
+        .send()?
+        .error_for_status()?
+        .json::<EngineDirectory>()?;
+
+    directory
+        .engines
+        .into_iter()
+        .find(|engine| engine.name.eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("No engine named {} in the engine directory", name).into())
+}
+
 #[cfg(target_os = "macos")]
 fn cpu_architecture() -> &'static str {
     "default"
@@ -45,7 +72,15 @@ fn cpu_architecture() -> &'static str {
     }
 }
 
-pub fn install(engine: Engine) -> PathBuf {
+fn send_install_status(sink: &dyn EventSink, engine: &Engine, status: String) {
+    info!("Installing {}: {}", engine.name, status);
+    sink.send(
+        "engine_directory::install",
+        json!({ "engine": engine.name, "status": status }),
+    );
+}
+
+pub fn install(engine: Engine, sink: &dyn EventSink) -> PathBuf {
     let engines_path = get_app_data_dir().join("engines");
 
     assert!(
@@ -54,18 +89,17 @@ pub fn install(engine: Engine) -> PathBuf {
         engines_path.to_str().unwrap()
     );
 
-    println!(
-        "Downloading engine {} to {}",
-        engine.name,
-        engines_path.to_str().unwrap()
-    );
-
     let architecture = cpu_architecture();
 
-    println!(
-        "os: {}, architecture: {}",
-        std::env::consts::OS,
-        architecture
+    send_install_status(
+        sink,
+        &engine,
+        format!(
+            "Downloading to {} (os: {}, architecture: {})",
+            engines_path.to_str().unwrap(),
+            std::env::consts::OS,
+            architecture
+        ),
     );
 
     let binary = engine
@@ -87,10 +121,12 @@ pub fn install(engine: Engine) -> PathBuf {
 
     let zip_path = engines_path.join(filename);
 
-    let mut resp = reqwest::blocking::get(&binary.zip).unwrap();
+    let mut resp = http::client().unwrap().get(&binary.zip).send().unwrap();
     let mut file = File::create(&zip_path).unwrap();
     io::copy(&mut resp, &mut file).unwrap();
 
+    send_install_status(sink, &engine, format!("Extracting {}", filename));
+
     if binary.zip.ends_with(".zip") {
         let mut archive =
             zip::ZipArchive::new(File::open(&zip_path).unwrap()).expect("Error opening zip file");
@@ -134,10 +170,14 @@ pub fn install(engine: Engine) -> PathBuf {
 
     let path_to_binary = engines_path.join(&binary.binary_filename);
 
-    println!("path_to_binary: {}", path_to_binary.to_str().unwrap());
-
     make_engine_executable(&path_to_binary);
 
+    send_install_status(
+        sink,
+        &engine,
+        format!("Installed to {}", path_to_binary.to_str().unwrap()),
+    );
+
     path_to_binary
 }
 
@@ -0,0 +1,392 @@
+//! Asks an engine binary about itself and derives the limits to register it
+//! with, so users don't have to guess threads and hash. Also checks that a
+//! binary works before it's linked to an engine.
+//!
+//! The engine sandbox and limits are options of a linked engine, so binaries
+//! are run here without them, at the priority and on the cores the settings
+//! give engines.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    error::Error,
+    io::{BufRead, BufReader, Write},
+    process::{Child, ChildStdin, Command, Stdio},
+    sync::{mpsc, Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use sysinfo::{System, SystemExt};
+
+use crate::{
+    db::EngineOptions,
+    lichess_api::{ApiError, NewEngine},
+    sandbox::{self, Placement},
+    settings, uci,
+};
+
+/// How long an engine gets to answer `uci`
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long the test search runs, in milliseconds
+const TEST_MOVETIME_MS: u64 = 1000;
+/// How late the test search's `bestmove` may be
+const TEST_SEARCH_GRACE: Duration = Duration::from_secs(5);
+
+/// Lines of stderr kept for the test result
+const STDERR_LINES: usize = 50;
+
+/// Memory left to the OS and other programs, as a share of the total
+const MEMORY_RESERVE_RATIO: f64 = 0.3;
+/// ...but at least this much, in MB
+const MIN_MEMORY_RESERVE_MB: u64 = 1024;
+
+/// Smallest hash Lichess accepts, in MB
+const MIN_HASH_MB: u32 = 16;
+
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct EngineInfo {
+    pub name: Option<String>,
+    pub author: Option<String>,
+    pub options: Vec<uci::EngineOption>,
+}
+
+impl EngineInfo {
+    pub fn option(&self, name: &str) -> Option<&uci::EngineOption> {
+        self.options
+            .iter()
+            .find(|option| option.name.eq_ignore_ascii_case(name))
+    }
+}
+
+/// What [`test`] found out about a working engine
+#[derive(Clone, Debug, Serialize)]
+pub struct EngineTest {
+    #[serde(flatten)]
+    pub info: EngineInfo,
+    pub best_move: String,
+    /// Nodes per second during the test search, if the engine reported them
+    pub nps: Option<u64>,
+    /// What the engine wrote to stderr, if anything
+    pub stderr: String,
+}
+
+/// A running engine, read from on other threads so an engine that never
+/// answers can be killed
+struct Session {
+    process: Child,
+    stdin: ChildStdin,
+    lines: mpsc::Receiver<String>,
+    stderr: Arc<Mutex<VecDeque<String>>>,
+    stderr_reader: JoinHandle<()>,
+}
+
+impl Session {
+    fn start(binary_location: &str) -> Result<Session, Box<dyn Error>> {
+        let mut process = Command::new(binary_location);
+        process
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+
+        // no sandbox or limits, those come with linking the binary
+        let options = EngineOptions::default();
+        let placement = Placement::new(&options, &settings::get());
+        // kept until the engine is spawned
+        let _sandbox = sandbox::configure(&mut process, binary_location, &options, &placement)?;
+
+        let mut process = process
+            .spawn()
+            .map_err(|e| format!("Failed to start engine: {}", e))?;
+        let stdin = process.stdin.take().ok_or("Engine stdin unavailable")?;
+        let stdout = process.stdout.take().ok_or("Engine stdout unavailable")?;
+        let stderr = process.stderr.take().ok_or("Engine stderr unavailable")?;
+
+        let (tx, lines) = mpsc::channel();
+        thread::spawn(move || {
+            for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let stderr_lines = Arc::new(Mutex::new(VecDeque::new()));
+        let collected = stderr_lines.clone();
+        let stderr_reader = thread::spawn(move || {
+            for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                let mut collected = collected.lock().unwrap();
+                if collected.len() == STDERR_LINES {
+                    collected.pop_front();
+                }
+                collected.push_back(line);
+            }
+        });
+
+        Ok(Session {
+            process,
+            stdin,
+            lines,
+            stderr: stderr_lines,
+            stderr_reader,
+        })
+    }
+
+    fn send(&mut self, command: &str) -> Result<(), Box<dyn Error>> {
+        writeln!(self.stdin, "{}", command)?;
+        self.stdin.flush()?;
+        Ok(())
+    }
+
+    /// Hands each line to `on_line` until it returns true, failing if that
+    /// takes longer than `timeout`
+    fn read_until(
+        &mut self,
+        timeout: Duration,
+        waiting_for: &str,
+        mut on_line: impl FnMut(&str) -> bool,
+    ) -> Result<(), Box<dyn Error>> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match self.lines.recv_timeout(remaining) {
+                Ok(line) => {
+                    if on_line(line.trim()) {
+                        return Ok(());
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    return Err(format!("Engine did not answer {} in time", waiting_for).into())
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => {
+                    return Err(format!("Engine exited while waiting for {}", waiting_for).into())
+                }
+            }
+        }
+    }
+
+    fn handshake(&mut self) -> Result<EngineInfo, Box<dyn Error>> {
+        self.send("uci")?;
+
+        let mut info = EngineInfo::default();
+        self.read_until(HANDSHAKE_TIMEOUT, "the UCI handshake", |line| {
+            if line == "uciok" {
+                return true;
+            }
+            if let Some(option) = uci::EngineOption::parse(line) {
+                info.options.push(option);
+            } else if let Some((key, value)) = uci::parse_id(line) {
+                match key {
+                    "name" => info.name = Some(value.to_string()),
+                    "author" => info.author = Some(value.to_string()),
+                    _ => {}
+                }
+            }
+            false
+        })?;
+
+        Ok(info)
+    }
+
+    /// Stops the engine and returns what it wrote to stderr
+    fn stop(mut self) -> String {
+        let _ = writeln!(self.stdin, "quit");
+        drop(self.stdin);
+        let _ = self.process.kill();
+        let _ = self.process.wait();
+        // the pipe is closed now, let the reader catch up
+        let _ = self.stderr_reader.join();
+
+        let stderr = self.stderr.lock().unwrap();
+        stderr.iter().cloned().collect::<Vec<_>>().join("\n")
+    }
+}
+
+/// Starts the engine (unsandboxed), sends `uci` and collects its id and
+/// options
+pub fn probe(binary_location: &str) -> Result<EngineInfo, Box<dyn Error>> {
+    let mut session = Session::start(binary_location)?;
+    let result = session.handshake();
+    session.stop();
+    result
+}
+
+/// Checks that the binary is a working UCI engine: it has to complete the
+/// handshake and find a move in the start position. Runs unsandboxed.
+pub fn test(binary_location: &str) -> Result<EngineTest, Box<dyn Error>> {
+    let mut session = Session::start(binary_location)?;
+
+    let mut nps = None;
+    let mut best_move = String::new();
+    let result = session.handshake().and_then(|info| {
+        session.send("isready")?;
+        session.read_until(HANDSHAKE_TIMEOUT, "isready", |line| line == "readyok")?;
+
+        session.send("position startpos")?;
+        session.send(&format!("go movetime {}", TEST_MOVETIME_MS))?;
+        session.read_until(
+            Duration::from_millis(TEST_MOVETIME_MS) + TEST_SEARCH_GRACE,
+            "the test search",
+            |line| {
+                if let Some(info) = uci::Info::parse(line) {
+                    nps = match (info.nps, info.nodes, info.time) {
+                        (Some(nps), _, _) => Some(nps),
+                        (None, Some(nodes), Some(time)) if time > 0 => Some(nodes * 1000 / time),
+                        _ => nps,
+                    };
+                } else if let Some(bestmove) = line.strip_prefix("bestmove ") {
+                    best_move = bestmove.split_whitespace().next().unwrap_or("").to_string();
+                    return true;
+                }
+                false
+            },
+        )?;
+
+        if best_move.is_empty() || best_move == "(none)" || best_move == "0000" {
+            return Err("Engine found no move in the start position".into());
+        }
+        Ok(info)
+    });
+    let stderr = session.stop();
+
+    match result {
+        Ok(info) => Ok(EngineTest {
+            info,
+            best_move,
+            nps,
+            stderr,
+        }),
+        Err(e) if stderr.is_empty() => Err(e),
+        Err(e) => Err(format!("{}\n{}", e, stderr).into()),
+    }
+}
+
+/// Like [`test`], failing with an error on the form's binary location field
+pub fn check_binary(binary_location: &str) -> Result<EngineTest, ApiError> {
+    test(binary_location).map_err(|e| {
+        ApiError::Invalid(HashMap::from([(
+            "binaryLocation".to_string(),
+            vec![e.to_string()],
+        )]))
+    })
+}
+
+/// Lichess' name for a variant, from the names engines use for `UCI_Variant`
+fn lichess_variant(name: &str) -> Option<&'static str> {
+    match name.to_ascii_lowercase().as_str() {
+        "chess" | "standard" => Some("chess"),
+        "crazyhouse" => Some("crazyhouse"),
+        "antichess" | "giveaway" => Some("antichess"),
+        "atomic" => Some("atomic"),
+        "horde" => Some("horde"),
+        "kingofthehill" | "koth" => Some("kingofthehill"),
+        "racingkings" => Some("racingkings"),
+        "3check" | "threecheck" => Some("3check"),
+        _ => None,
+    }
+}
+
+/// Threads: the machine's physical cores, within the engine's `Threads` range.
+/// Hash: the largest power of two that fits in memory minus a reserve, within
+/// the engine's `Hash` range.
+/// Variants: those of the engine's `UCI_Variant` that Lichess supports.
+pub fn suggested_engine(name: &str, info: &EngineInfo) -> NewEngine {
+    let mut sys = System::new();
+    sys.refresh_cpu();
+    sys.refresh_memory();
+
+    let cores = sys
+        .physical_core_count()
+        .unwrap_or_else(|| sys.cpus().len())
+        .max(1) as u32;
+    let max_threads = match info.option("Threads").and_then(|option| option.max) {
+        Some(max) => cores.min(max.max(1) as u32),
+        None => cores,
+    };
+
+    let total_mb = sys.total_memory() / 1024 / 1024;
+    let reserve_mb = ((total_mb as f64 * MEMORY_RESERVE_RATIO) as u64).max(MIN_MEMORY_RESERVE_MB);
+    let mut available_mb = total_mb.saturating_sub(reserve_mb);
+    if let Some(max) = info.option("Hash").and_then(|option| option.max) {
+        available_mb = available_mb.min(max.max(0) as u64);
+    }
+    let max_hash = (0..)
+        .map(|exponent| u64::from(MIN_HASH_MB) << exponent)
+        .take_while(|megabytes| *megabytes <= available_mb)
+        .last()
+        .map_or(MIN_HASH_MB, |megabytes| {
+            megabytes.min(u64::from(u32::MAX)) as u32
+        });
+
+    let mut variants: Vec<String> = Vec::new();
+    if let Some(option) = info.option("UCI_Variant") {
+        for variant in option.vars.iter().filter_map(|var| lichess_variant(var)) {
+            if !variants.iter().any(|known| known == variant) {
+                variants.push(variant.to_string());
+            }
+        }
+    }
+    if variants.is_empty() {
+        variants.push("chess".to_string());
+    }
+
+    NewEngine {
+        name: name.to_string(),
+        max_threads,
+        max_hash,
+        variants,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn info(options: &[&str]) -> EngineInfo {
+        EngineInfo {
+            name: Some("Stockfish 16".to_string()),
+            author: None,
+            options: options
+                .iter()
+                .filter_map(|line| uci::EngineOption::parse(line))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn keeps_within_the_engine_limits() {
+        let engine = suggested_engine(
+            "Stockfish",
+            &info(&[
+                "option name Threads type spin default 1 min 1 max 1",
+                "option name Hash type spin default 16 min 1 max 40",
+            ]),
+        );
+
+        assert_eq!(engine.name, "Stockfish");
+        assert_eq!(engine.max_threads, 1);
+        // powers of two from the minimum Lichess accepts
+        assert!(engine.max_hash == MIN_HASH_MB || engine.max_hash == 2 * MIN_HASH_MB);
+        assert_eq!(engine.variants, ["chess"]);
+    }
+
+    #[test]
+    fn maps_variants_to_lichess_names() {
+        let engine = suggested_engine(
+            "Fairy-Stockfish",
+            &info(&[
+                "option name UCI_Variant type combo default chess var chess var giveaway \
+                 var antichess var 3check var shogi var kingofthehill",
+            ]),
+        );
+
+        assert_eq!(
+            engine.variants,
+            ["chess", "antichess", "3check", "kingofthehill"]
+        );
+        assert!(engine.max_threads >= 1);
+        assert!(engine.max_hash >= MIN_HASH_MB && engine.max_hash.is_power_of_two());
+    }
+}
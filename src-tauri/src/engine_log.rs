@@ -0,0 +1,215 @@
+//! Keeps what engines write to stderr, and how they exit, in a log file per
+//! engine under `engine-logs` in the app data dir.
+//!
+//! GUI builds have no terminal for an inherited stderr, so without this a
+//! crashing engine leaves nothing behind. The last lines of output are also
+//! kept in memory to report a crash right away.
+
+use std::{
+    collections::VecDeque,
+    fs::{self, File, OpenOptions},
+    io::{BufRead, BufReader, Read, Write},
+    path::PathBuf,
+    process::ExitStatus,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+
+use chrono::Local;
+use tracing::warn;
+
+use crate::utils::get_app_data_dir;
+
+/// Size after which a log is moved to `.log.1`, replacing the older one
+const MAX_LOG_BYTES: u64 = 1024 * 1024;
+/// Lines of output kept in memory for crash reports
+const TAIL_LINES: usize = 20;
+
+/// The log file of an engine, rotated once it grows too big
+struct LogFile {
+    path: PathBuf,
+    file: Option<File>,
+    written: u64,
+}
+
+impl LogFile {
+    fn open(engine_id: &str) -> LogFile {
+        let path = log_path(engine_id);
+        let mut log = LogFile {
+            written: fs::metadata(&path).map_or(0, |metadata| metadata.len()),
+            path,
+            file: None,
+        };
+        log.reopen();
+        log
+    }
+
+    fn reopen(&mut self) {
+        if self.written >= MAX_LOG_BYTES {
+            if let Err(e) = fs::rename(&self.path, self.path.with_extension("log.1")) {
+                warn!("Error rotating {}: {}", self.path.display(), e);
+            }
+            self.written = 0;
+        }
+
+        let opened = self
+            .path
+            .parent()
+            .map_or(Ok(()), fs::create_dir_all)
+            .and_then(|_| {
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)
+            });
+        self.file = match opened {
+            Ok(file) => Some(file),
+            Err(e) => {
+                warn!("Error opening {}: {}", self.path.display(), e);
+                None
+            }
+        };
+    }
+
+    fn write(&mut self, line: &str) {
+        if self.written >= MAX_LOG_BYTES {
+            self.reopen();
+        }
+
+        let line = format!("{} {}\n", Local::now().format("%Y-%m-%d %H:%M:%S"), line);
+        if let Some(file) = &mut self.file {
+            if file.write_all(line.as_bytes()).is_ok() {
+                self.written += line.len() as u64;
+            }
+        }
+    }
+}
+
+/// Where an engine's log is written. Engine ids come from Lichess, so only
+/// the characters they're made of are kept.
+pub fn log_path(engine_id: &str) -> PathBuf {
+    let name: String = engine_id
+        .chars()
+        .filter(|c| c.is_ascii_alphanumeric() || *c == '_' || *c == '-')
+        .collect();
+
+    logs_dir().join(format!("{}.log", name))
+}
+
+pub fn logs_dir() -> PathBuf {
+    get_app_data_dir().join("engine-logs")
+}
+
+/// Logs a single engine process, from spawn until it has exited
+pub struct EngineLog {
+    file: Arc<Mutex<LogFile>>,
+    tail: Arc<Mutex<VecDeque<String>>>,
+    stderr_reader: Option<JoinHandle<()>>,
+}
+
+impl EngineLog {
+    /// Starts logging the engine's stderr on another thread
+    pub fn start(
+        engine_id: &str,
+        binary_location: &str,
+        stderr: Option<impl Read + Send + 'static>,
+    ) -> EngineLog {
+        let mut file = LogFile::open(engine_id);
+        file.write(&format!("started {}", binary_location));
+
+        let file = Arc::new(Mutex::new(file));
+        let tail = Arc::new(Mutex::new(VecDeque::new()));
+
+        let stderr_reader = stderr.map(|stderr| {
+            let file = file.clone();
+            let tail = tail.clone();
+            thread::spawn(move || {
+                for line in BufReader::new(stderr).lines().map_while(Result::ok) {
+                    file.lock().unwrap().write(&line);
+                    push_line(&tail, line);
+                }
+            })
+        });
+
+        EngineLog {
+            file,
+            tail,
+            stderr_reader,
+        }
+    }
+
+    /// Keeps a line the engine wrote to stdout for the crash report. They're
+    /// not written to the file, there are far too many.
+    pub fn output(&self, line: &str) {
+        push_line(&self.tail, line.to_string());
+    }
+
+    /// Records how the engine exited and returns the last lines of output
+    pub fn finish(mut self, status: Result<ExitStatus, String>) -> Vec<String> {
+        // stderr is closed once the engine has exited
+        if let Some(stderr_reader) = self.stderr_reader.take() {
+            let _ = stderr_reader.join();
+        }
+
+        let exit = match status {
+            Ok(status) => format!("exited with {}", describe(&status)),
+            Err(e) => format!("exit status unknown: {}", e),
+        };
+        self.file.lock().unwrap().write(&exit);
+
+        let tail = self.tail.lock().unwrap();
+        tail.iter().cloned().collect()
+    }
+}
+
+fn push_line(tail: &Mutex<VecDeque<String>>, line: String) {
+    let mut tail = tail.lock().unwrap();
+    if tail.len() == TAIL_LINES {
+        tail.pop_front();
+    }
+    tail.push_back(line);
+}
+
+/// `exit code 1`, or the signal that ended the process on Unix
+pub fn describe(status: &ExitStatus) -> String {
+    #[cfg(unix)]
+    {
+        use std::os::unix::process::ExitStatusExt;
+
+        if let Some(signal) = status.signal() {
+            let name = match signal {
+                libc::SIGSEGV => " (SIGSEGV)",
+                libc::SIGILL => " (SIGILL, the binary may not support this CPU)",
+                libc::SIGABRT => " (SIGABRT)",
+                libc::SIGBUS => " (SIGBUS)",
+                libc::SIGFPE => " (SIGFPE)",
+                libc::SIGKILL => " (SIGKILL)",
+                libc::SIGXCPU => " (SIGXCPU, CPU time limit reached)",
+                _ => "",
+            };
+            return format!("signal {}{}", signal, name);
+        }
+    }
+
+    match status.code() {
+        Some(code) => format!("exit code {}", code),
+        None => "unknown status".to_string(),
+    }
+}
+
+/// The last lines of an engine's log, including the rotated one
+pub fn recent(engine_id: &str, lines: usize) -> Result<Vec<String>, String> {
+    let path = log_path(engine_id);
+
+    let mut recent: Vec<String> = Vec::new();
+    for path in [path.with_extension("log.1"), path] {
+        match fs::read_to_string(&path) {
+            Ok(contents) => recent.extend(contents.lines().map(String::from)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => return Err(format!("Error reading {}: {}", path.display(), e)),
+        }
+    }
+
+    let skip = recent.len().saturating_sub(lines);
+    Ok(recent.split_off(skip))
+}
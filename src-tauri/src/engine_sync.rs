@@ -0,0 +1,165 @@
+//! Finds where the local `engines` table and the engines registered on
+//! Lichess disagree, and repairs it.
+//!
+//! They drift when an engine is deleted while offline, when its binary is
+//! moved, or when another machine registers engines with the same account.
+
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use tracing::warn;
+
+use crate::{
+    accounts, db, engine_info,
+    events::EventSink,
+    lichess::{send_status_to_frontend, StatusLevel, StatusPayload},
+    lichess_api::{ApiError, ExternalEngine, LichessApi},
+};
+
+#[derive(Debug, Serialize)]
+pub struct LocalEngine {
+    pub engine_id: String,
+    pub binary_location: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct RemoteEngine {
+    pub account_id: i32,
+    pub engine: ExternalEngine,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct SyncReport {
+    /// Linked locally, but no longer registered on any account
+    pub orphaned: Vec<LocalEngine>,
+    /// Registered on Lichess without a local binary, so its work would fail
+    pub unlinked: Vec<RemoteEngine>,
+    /// Linked to a binary that's no longer on disk
+    pub missing_binaries: Vec<LocalEngine>,
+    /// Accounts that couldn't be checked
+    pub errors: Vec<String>,
+}
+
+impl SyncReport {
+    pub fn issue_count(&self) -> usize {
+        self.orphaned.len() + self.unlinked.len() + self.missing_binaries.len()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "snake_case")]
+pub enum Repair {
+    /// Forget the local link of an engine Lichess no longer knows
+    Forget { engine_id: String },
+    /// Link a registered engine to a binary
+    Link {
+        engine_id: String,
+        binary_location: String,
+    },
+    /// Delete a registered engine from Lichess
+    Unregister { account_id: i32, engine_id: String },
+}
+
+pub fn check() -> SyncReport {
+    let mut report = SyncReport::default();
+    let mut remote_ids = Vec::new();
+    // an engine only counts as orphaned if every account could be asked,
+    // otherwise it may belong to the one that wasn't
+    let mut checked_all = true;
+
+    for account in accounts::all() {
+        if account.lichess_token.is_none() {
+            checked_all = false;
+            continue;
+        }
+
+        let engines = LichessApi::for_account(&account).and_then(|api| api.list_engines());
+        match engines {
+            Ok(engines) => {
+                for engine in engines {
+                    remote_ids.push(engine.id.clone());
+                    if db::get_engine_binary_path(&engine.id).is_none() {
+                        report.unlinked.push(RemoteEngine {
+                            account_id: account.id,
+                            engine,
+                        });
+                    }
+                }
+            }
+            Err(e) => {
+                checked_all = false;
+                report.errors.push(format!(
+                    "{} on {}: {}",
+                    account.username.unwrap_or_default(),
+                    account.lichess_host,
+                    e
+                ));
+            }
+        }
+    }
+
+    for engine in db::get_all_engine_binary_paths() {
+        let local = LocalEngine {
+            engine_id: engine.engine_id,
+            binary_location: engine.binary_location,
+        };
+
+        if checked_all && !remote_ids.contains(&local.engine_id) {
+            report.orphaned.push(local);
+        } else if !Path::new(&local.binary_location).is_file() {
+            report.missing_binaries.push(local);
+        }
+    }
+
+    report
+}
+
+pub fn repair(repair: Repair) -> Result<(), ApiError> {
+    match repair {
+        Repair::Forget { engine_id } => db::delete_engine(&engine_id),
+        Repair::Link {
+            engine_id,
+            binary_location,
+        } => {
+            engine_info::check_binary(&binary_location)?;
+
+            // an engine whose binary moved keeps its sandbox and limits
+            db::set_engine_binary_location(&engine_id, &binary_location);
+        }
+        Repair::Unregister {
+            account_id,
+            engine_id,
+        } => {
+            let account = accounts::get(account_id).ok_or(ApiError::NotLoggedIn)?;
+            match LichessApi::for_account(&account)?.delete_engine(&engine_id) {
+                Ok(_) | Err(ApiError::NotFound) => db::delete_engine(&engine_id),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Checks once at startup and points the user to the engines page if
+/// anything needs attention
+pub fn check_at_startup(sink: &dyn EventSink) {
+    let report = check();
+
+    for error in &report.errors {
+        warn!("Error checking engines: {}", error);
+    }
+
+    if report.issue_count() > 0 {
+        send_status_to_frontend(
+            sink,
+            StatusPayload {
+                status: format!(
+                    "{} engine(s) out of sync with Lichess, see the Engines page",
+                    report.issue_count()
+                ),
+                level: StatusLevel::Error,
+            },
+        );
+    }
+}
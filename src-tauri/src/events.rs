@@ -0,0 +1,96 @@
+//! Where the backend sends its events (worker status, UCI output, login and
+//! installer progress). The GUI forwards them to the webview, headless mode
+//! prints them, and tests can collect them from a channel.
+
+use std::sync::Arc;
+#[cfg(test)]
+use std::sync::{
+    mpsc::{self, Receiver, Sender},
+    Mutex,
+};
+
+use serde::Serialize;
+use serde_json::Value;
+use tauri::{AppHandle, Manager};
+use tracing::{debug, error, info};
+
+pub trait EventSink: Send + Sync {
+    fn emit(&self, event: &str, payload: Value);
+}
+
+impl dyn EventSink + '_ {
+    pub fn send<P: Serialize>(&self, event: &str, payload: P) {
+        match serde_json::to_value(payload) {
+            Ok(payload) => self.emit(event, payload),
+            Err(e) => error!("Error serializing payload for {}: {}", event, e),
+        }
+    }
+}
+
+/// Emits to every window of the Tauri app
+pub struct TauriSink(pub AppHandle);
+
+impl EventSink for TauriSink {
+    fn emit(&self, event: &str, payload: Value) {
+        debug!("event: {} | {}", event, payload);
+
+        if let Err(e) = self.0.emit_all(event, payload) {
+            error!("Error emitting {}: {}", event, e);
+        }
+    }
+}
+
+/// Adds the id of the account an event belongs to, so the UI can tell the
+/// provider loops of several accounts apart
+pub struct AccountSink {
+    pub inner: Arc<dyn EventSink>,
+    pub account_id: i32,
+}
+
+impl EventSink for AccountSink {
+    fn emit(&self, event: &str, mut payload: Value) {
+        if let Value::Object(fields) = &mut payload {
+            fields.insert("account_id".to_string(), self.account_id.into());
+        }
+
+        self.inner.emit(event, payload);
+    }
+}
+
+/// Prints events to stdout, for headless mode
+pub struct LogSink;
+
+impl EventSink for LogSink {
+    fn emit(&self, event: &str, payload: Value) {
+        info!("event: {} | {}", event, payload);
+    }
+}
+
+/// Sends events over a channel so they can be inspected
+#[cfg(test)]
+pub struct ChannelSink(Mutex<Sender<(String, Value)>>);
+
+#[cfg(test)]
+impl ChannelSink {
+    pub fn new() -> (ChannelSink, Receiver<(String, Value)>) {
+        let (tx, rx) = mpsc::channel();
+        (ChannelSink(Mutex::new(tx)), rx)
+    }
+}
+
+#[cfg(test)]
+impl EventSink for ChannelSink {
+    fn emit(&self, event: &str, payload: Value) {
+        // the receiver going away just means nobody is listening anymore
+        let _ = self.0.lock().unwrap().send((event.to_string(), payload));
+    }
+}
+
+/// Discards every event
+#[cfg(test)]
+pub struct NoopSink;
+
+#[cfg(test)]
+impl EventSink for NoopSink {
+    fn emit(&self, _event: &str, _payload: Value) {}
+}
@@ -0,0 +1,372 @@
+//! Runs the engine provider without the Tauri window, for headless machines.
+//!
+//! Everything is stored in the same SQLite database as the desktop app, so a
+//! machine can be set up with the CLI and later opened with the GUI (or the
+//! other way around). Status is printed to stdout, which ends up in journald
+//! when running as a systemd service.
+
+use std::{error::Error, fs, path::Path, sync::Arc};
+
+use crate::{
+    accounts, db, engine_directory,
+    engine_info::{self, EngineTest},
+    engine_log, engine_sync,
+    events::LogSink,
+    lichess,
+    lichess_api::LichessApi,
+    logging, login,
+    sandbox::{self, Placement},
+    settings::{self, TomlBackend},
+};
+
+const USAGE: &str = "Usage: lichess-tauri --headless [--config <settings.toml>] <command>
+
+Commands:
+  login --token <token>              Log in with a personal API token (engine:read, engine:write)
+  logout                             Revoke and forget the stored token
+  engine add <engine_id> <binary>    Link an engine registered on Lichess to a local binary,
+                                     or relink one already linked
+  engine remove <engine_id>          Unlink an engine
+  engine test <binary>               Check that a binary is a working UCI engine
+  engine list                        List linked engines
+  engine install <name>              Download an engine from the engine directory and register it
+  engine sync                        Compare linked engines with the ones registered on Lichess
+  engine set <engine_id> <option> <value>
+                                     Set how an engine is started: sandbox (true/false, Linux
+                                     only), memory_limit_mb, cpu_time_limit_secs, nice, cpus
+                                     (like 0-3,6, Linux only), free_cores, or allowed_paths
+                                     (separated by ':'). An empty value clears it.
+  engine status                      Show the priority and cores each engine runs with
+  engine logs <engine_id>            Show what an engine wrote to stderr and how it exited
+  account list                       List the accounts analysis is provided to
+  account add <lichess_host> <engine_host> <token>
+                                     Add another account with a personal API token, or
+                                     replace the token of one already added
+  account remove <id>                Remove an account
+  account enable <id>                Provide analysis to an account
+  account disable <id>               Stop providing analysis to an account
+  account rotate-secret <id>         Replace the provider secret of an account (0 is the default)
+  logs                               Show the last lines of the app's log
+  diagnostics <zip>                  Save logs, system info and settings (without secrets) for a
+                                     bug report
+  run                                Provide analysis until stopped
+
+With --config, settings are read from and saved to the given TOML file instead
+of the app database. Top-level keys are settings (lichess_host, engine_host,
+provider_secret, ...) and an optional [engines] table maps engine ids to binary
+locations. provider_secret and lichess_token are read from the file if set
+there, and otherwise from the system's secret storage, where logging in and
+rotating the secret save them.";
+
+pub fn run(args: &[String]) -> i32 {
+    let mut args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    if let Some(index) = args.iter().position(|arg| *arg == "--config") {
+        let path = match args.get(index + 1) {
+            Some(path) => *path,
+            None => {
+                eprintln!("{}", USAGE);
+                return 2;
+            }
+        };
+
+        if let Err(e) = load_config(path) {
+            eprintln!("Error loading config {}: {}", path, e);
+            return 1;
+        }
+
+        args.drain(index..=index + 1);
+    }
+
+    let result = match args.as_slice() {
+        ["login", "--token", token] => login(token),
+        ["logout"] => {
+            login::logout();
+            Ok(())
+        }
+        ["engine", "add", engine_id, binary_location] => add_engine(engine_id, binary_location),
+        ["engine", "test", binary_location] => test_engine(binary_location).map(|_| ()),
+        ["engine", "remove", engine_id] => {
+            db::delete_engine(engine_id);
+            Ok(())
+        }
+        ["engine", "list"] => {
+            for engine in db::get_all_engine_binary_paths() {
+                println!("{}\t{}", engine.engine_id, engine.binary_location);
+            }
+            Ok(())
+        }
+        ["engine", "install", name] => install_engine(name),
+        ["engine", "sync"] => {
+            sync_engines();
+            Ok(())
+        }
+        ["engine", "logs", engine_id] => print_lines(engine_log::recent(engine_id, 200)),
+        ["engine", "status"] => {
+            engine_status();
+            Ok(())
+        }
+        ["engine", "set", engine_id, option, value] => set_engine_option(engine_id, option, value),
+        ["account", rest @ ..] => account(rest),
+        ["logs"] => print_lines(logging::recent(200)),
+        ["diagnostics", path] => logging::export_diagnostics(Path::new(path), crate::get_sysinfo())
+            .map(|_| println!("Saved {}", path)),
+        ["run"] => run_providers(),
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    match result {
+        Ok(_) => 0,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            1
+        }
+    }
+}
+
+fn print_lines(lines: Result<Vec<String>, String>) -> Result<(), Box<dyn Error>> {
+    for line in lines? {
+        println!("{}", line);
+    }
+    Ok(())
+}
+
+fn load_config(path: &str) -> Result<(), Box<dyn Error>> {
+    let config: toml::Value = toml::from_str(&fs::read_to_string(path)?)?;
+
+    if let Some(engines) = config.get("engines").and_then(toml::Value::as_table) {
+        for (engine_id, binary_location) in engines {
+            let binary_location = binary_location.as_str().ok_or(format!(
+                "binary location for {} must be a string",
+                engine_id
+            ))?;
+
+            // only changed paths are tested, every command loads the config
+            let linked = db::get_engine(engine_id).map(|engine| engine.binary_location);
+            if linked.as_deref() != Some(binary_location) {
+                engine_info::test(binary_location)
+                    .map_err(|e| format!("engine {}: {}", engine_id, e))?;
+                db::set_engine_binary_location(engine_id, binary_location);
+            }
+        }
+    }
+
+    settings::use_backend(Box::new(TomlBackend::new(path)));
+
+    Ok(())
+}
+
+fn account(args: &[&str]) -> Result<(), Box<dyn Error>> {
+    match args {
+        ["list"] => {
+            for account in accounts::all() {
+                let status = if account.enabled {
+                    "enabled"
+                } else {
+                    "disabled"
+                };
+                let username = account.username.as_deref().unwrap_or("(not logged in)");
+                println!(
+                    "{}\t{}\t{}\t{}",
+                    account.id, account.lichess_host, username, status
+                );
+            }
+        }
+        ["add", lichess_host, engine_host, token] => {
+            let account = accounts::add(lichess_host, engine_host, token)?;
+            println!(
+                "Added {} as account {}",
+                account.username.unwrap_or_default(),
+                account.id
+            );
+        }
+        ["remove", id] => accounts::remove(parse_account_id(id)?)?,
+        ["enable", id] => accounts::set_enabled(parse_account_id(id)?, true)?,
+        ["disable", id] => accounts::set_enabled(parse_account_id(id)?, false)?,
+        ["rotate-secret", id] => {
+            let account = accounts::get(parse_account_id(id)?).ok_or("No such account")?;
+            accounts::rotate_provider_secret(&account, &LichessApi::for_account(&account)?)?;
+            println!("Provider secret replaced");
+        }
+        _ => return Err(USAGE.into()),
+    }
+
+    Ok(())
+}
+
+fn parse_account_id(id: &str) -> Result<i32, String> {
+    id.parse().map_err(|_| format!("Invalid account id {}", id))
+}
+
+fn run_providers() -> Result<(), Box<dyn Error>> {
+    std::thread::spawn(|| login::watch_token(&LogSink));
+
+    let providers = lichess::start_providers(Arc::new(LogSink));
+    if providers.is_empty() {
+        return Err("No enabled accounts, run `account enable <id>` first".into());
+    }
+
+    for provider in providers {
+        let _ = provider.join();
+    }
+
+    Ok(())
+}
+
+fn test_engine(binary_location: &str) -> Result<EngineTest, Box<dyn Error>> {
+    let test = engine_info::test(binary_location)?;
+
+    println!(
+        "{} by {}",
+        test.info.name.as_deref().unwrap_or("Unnamed engine"),
+        test.info.author.as_deref().unwrap_or("unknown")
+    );
+    println!("{} options", test.info.options.len());
+    println!("bestmove {} in the start position", test.best_move);
+    if let Some(nps) = test.nps {
+        println!("{} nodes per second", nps);
+    }
+    if !test.stderr.is_empty() {
+        println!("stderr:\n{}", test.stderr);
+    }
+
+    Ok(test)
+}
+
+fn add_engine(engine_id: &str, binary_location: &str) -> Result<(), Box<dyn Error>> {
+    test_engine(binary_location)?;
+
+    if let Some(engine) = db::get_engine(engine_id) {
+        println!(
+            "{} was linked to {}, relinking",
+            engine_id, engine.binary_location
+        );
+    }
+    db::set_engine_binary_location(engine_id, binary_location);
+    Ok(())
+}
+
+fn engine_status() {
+    let settings = settings::get();
+
+    for engine in db::get_all_engine_binary_paths() {
+        let placement = Placement::new(&engine.options(), &settings);
+        let cpus = match placement.cpus {
+            Some(cpus) => cpus
+                .iter()
+                .map(usize::to_string)
+                .collect::<Vec<_>>()
+                .join(","),
+            None => "all".to_string(),
+        };
+        println!(
+            "{}\tnice {}\tcores {}",
+            engine.engine_id,
+            placement.nice.unwrap_or(0),
+            cpus
+        );
+    }
+}
+
+fn set_engine_option(engine_id: &str, option: &str, value: &str) -> Result<(), Box<dyn Error>> {
+    let engine = db::get_engine(engine_id).ok_or("Unknown engine, run `engine list`")?;
+    let mut options = engine.options();
+
+    let number = || -> Result<Option<i32>, Box<dyn Error>> {
+        match value.trim() {
+            "" => Ok(None),
+            value => Ok(Some(value.parse()?)),
+        }
+    };
+    match option {
+        "sandbox" => options.sandbox = settings::parse_bool(option, value)?,
+        "memory_limit_mb" => options.memory_limit_mb = number()?,
+        "cpu_time_limit_secs" => options.cpu_time_limit_secs = number()?,
+        "nice" => options.nice = number()?,
+        "cpus" => {
+            options.cpus = match value.trim() {
+                "" => None,
+                value => Some(value.to_string()),
+            }
+        }
+        "free_cores" => options.free_cores = number()?,
+        "allowed_paths" => options.allowed_paths = value.split(':').collect::<Vec<_>>().join("\n"),
+        _ => return Err(format!("Unknown engine option {}", option).into()),
+    }
+
+    sandbox::validate(&options)?;
+    db::update_engine_options(engine_id, &options);
+    Ok(())
+}
+
+fn sync_engines() {
+    let report = engine_sync::check();
+
+    for engine in &report.orphaned {
+        println!(
+            "{}\tno longer registered on Lichess, run `engine remove {}`",
+            engine.engine_id, engine.engine_id
+        );
+    }
+    for remote in &report.unlinked {
+        println!(
+            "{}\t{} is registered without a local binary, run `engine add {} <binary>`",
+            remote.engine.id, remote.engine.name, remote.engine.id
+        );
+    }
+    for engine in &report.missing_binaries {
+        println!(
+            "{}\tbinary {} is missing, run `engine remove` and `engine add` again",
+            engine.engine_id, engine.binary_location
+        );
+    }
+    for error in &report.errors {
+        println!("Error: {}", error);
+    }
+
+    if report.issue_count() == 0 && report.errors.is_empty() {
+        println!("Engines are in sync with Lichess");
+    }
+}
+
+fn login(token: &str) -> Result<(), Box<dyn Error>> {
+    let username = login::login_with_token(token)?;
+
+    println!("Logged in as {}", username);
+
+    Ok(())
+}
+
+fn install_engine(name: &str) -> Result<(), Box<dyn Error>> {
+    let settings = settings::get();
+    let api_token = settings
+        .lichess_token
+        .ok_or("Not logged in, run `login` first")?;
+
+    let engine = engine_directory::find(name)?;
+    let engine_name = format!("{} {}", engine.name, engine.version);
+    let binary_location = engine_directory::install(engine, &LogSink)
+        .into_os_string()
+        .into_string()
+        .map_err(|_| "Engine path is not valid UTF-8")?;
+
+    let info = test_engine(&binary_location)?.info;
+    let registration =
+        engine_info::suggested_engine(&engine_name, &info).registration(&settings.provider_secret);
+
+    let engine_id = LichessApi::new(&settings.lichess_host, &api_token)?
+        .register_engine(&registration)?
+        .id;
+
+    db::add_engine(&engine_id, &binary_location);
+
+    println!(
+        "Installed {} as {} at {}",
+        engine_name, engine_id, binary_location
+    );
+
+    Ok(())
+}
@@ -0,0 +1,106 @@
+//! The HTTP client for everything the app downloads or sends, so requests to
+//! Lichess, the engine host and the engine directory all go through the
+//! configured proxy and certificates, with the same timeouts and user agent.
+
+use std::{env, fs, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::{
+    blocking::{Client, ClientBuilder, Response},
+    header, Certificate, Proxy, Url,
+};
+
+use crate::settings::{self, Settings};
+
+/// Proxy schemes reqwest can connect through
+const PROXY_SCHEMES: [&str; 4] = ["http", "https", "socks5", "socks5h"];
+
+/// `lichess-tauri/0.0.8 (linux; x86_64)`
+pub fn user_agent() -> String {
+    format!(
+        "lichess-tauri/{} ({}; {})",
+        env!("APP_VERSION"),
+        env::consts::OS,
+        env::consts::ARCH
+    )
+}
+
+/// A client builder set up from the current settings, for requests that need
+/// more (default headers, another timeout)
+pub fn builder() -> Result<ClientBuilder, String> {
+    builder_with(&settings::get())
+}
+
+/// [`builder`] set up from the given settings
+pub fn builder_with(settings: &Settings) -> Result<ClientBuilder, String> {
+    let mut builder = ClientBuilder::new()
+        .user_agent(user_agent())
+        .connect_timeout(Duration::from_secs(settings.connect_timeout_secs))
+        // applies to each read, so long downloads don't time out
+        .timeout(Duration::from_secs(settings.read_timeout_secs));
+
+    if let Some(proxy) = &settings.proxy {
+        builder = builder.proxy(parse_proxy(proxy)?);
+    }
+
+    if let Some(path) = &settings.ca_certificates {
+        for certificate in read_certificates(path)? {
+            builder = builder.add_root_certificate(certificate);
+        }
+    }
+
+    Ok(builder)
+}
+
+pub fn client() -> Result<Client, String> {
+    builder()?
+        .build()
+        .map_err(|e| format!("Error setting up the HTTP client: {}", e))
+}
+
+/// `http://`, `https://`, `socks5://` or `socks5h://` (DNS resolved by the
+/// proxy), optionally with `user:password@`
+pub fn parse_proxy(url: &str) -> Result<Proxy, String> {
+    let parsed = Url::parse(url.trim()).map_err(|e| e.to_string())?;
+    if !PROXY_SCHEMES.contains(&parsed.scheme()) {
+        return Err(format!(
+            "must start with {}://",
+            PROXY_SCHEMES.join("://, ")
+        ));
+    }
+
+    Proxy::all(parsed).map_err(|e| e.to_string())
+}
+
+/// Reads a PEM file with one or more certificates, trusted in addition to the
+/// system's (e.g. the CA of a proxy that inspects TLS)
+pub fn read_certificates(path: &str) -> Result<Vec<Certificate>, String> {
+    let pem = fs::read(path).map_err(|e| format!("Error reading {}: {}", path, e))?;
+    let certificates = Certificate::from_pem_bundle(&pem)
+        .map_err(|e| format!("Invalid certificates in {}: {}", path, e))?;
+
+    if certificates.is_empty() {
+        return Err(format!("No certificates in {}", path));
+    }
+
+    Ok(certificates)
+}
+
+/// How long the `Retry-After` header of a response asks to wait, given in
+/// seconds or as a date
+pub fn retry_after(response: &Response) -> Option<Duration> {
+    let value = response.headers().get(header::RETRY_AFTER)?.to_str().ok()?;
+
+    if let Ok(secs) = value.trim().parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value.trim()).ok()?;
+    // a date in the past means retrying right away
+    Some(
+        date.with_timezone(&Utc)
+            .signed_duration_since(Utc::now())
+            .to_std()
+            .unwrap_or_default(),
+    )
+}
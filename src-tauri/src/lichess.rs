@@ -1,18 +1,62 @@
 use std::{
+    collections::{BTreeMap, HashSet},
     error::Error,
-    io::{BufRead, BufReader, Write},
-    process::{Command, Stdio},
-    time::Duration,
+    io::{self, BufRead, BufReader, Write},
+    process::{Child, ChildStdin, ChildStdout, Command, ExitStatus, Stdio},
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
+use once_cell::sync::Lazy;
+
 use reqwest::{
-    blocking::{Body, ClientBuilder},
+    blocking::{Body, Client, Response},
     header::{self, HeaderMap},
+    StatusCode,
 };
 use serde::{Deserialize, Serialize};
-use tauri::{AppHandle, Manager};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    accounts::{self, Account, DEFAULT_ACCOUNT},
+    backoff::Backoff,
+    connectivity, db,
+    engine_log::{self, EngineLog},
+    events::{AccountSink, EventSink},
+    http,
+    lichess_api::ExternalEngine,
+    login,
+    resource_policy::{self, Decision},
+    sandbox::{self, Placement},
+    settings::{self, Settings},
+    uci,
+    watchdog::{Action, Watchdog},
+};
+
+/// How often a paused worker checks whether it may resume
+const RESOURCE_RECHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// How long an engine gets to exit after `quit` before it's killed
+const ENGINE_QUIT_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Lines of output shown when an engine crashes
+const CRASH_REPORT_LINES: usize = 5;
 
-use crate::db;
+/// How long sending the analysis may take beyond the search itself
+const SUBMISSION_GRACE: Duration = Duration::from_secs(30);
+
+/// How often the search limits are checked while the engine is silent
+const WATCHDOG_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Longest `Retry-After` honoured, unless the maximum backoff is longer
+const MAX_RATE_LIMIT_WAIT: Duration = Duration::from_secs(5 * 60);
+
+/// Accounts whose provider loop is running
+static RUNNING: Lazy<Mutex<HashSet<i32>>> = Lazy::new(|| Mutex::new(HashSet::new()));
 
 #[allow(dead_code)]
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -20,7 +64,7 @@ use crate::db;
 struct AnalysisRequest {
     id: String,
     work: Work,
-    engine: Engine,
+    engine: ExternalEngine,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -46,41 +90,42 @@ struct Work {
     moves: Vec<String>,
 }
 
-#[allow(dead_code)]
-#[derive(Clone, Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct Engine {
-    id: String,
-    name: String,
-    client_secret: String,
-    user_id: String,
-    max_threads: u32,
-    max_hash: u32,
-    variants: Vec<String>,
-    provider_data: Option<String>,
-}
-
-#[derive(Debug, Serialize, Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct Registration {
-    name: String,
-    max_threads: u32,
-    max_hash: u32,
-    variants: Vec<String>,
-    provider_secret: String,
-}
-
 #[derive(Debug, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct WorkRequest {
     provider_secret: String,
 }
 
+/// How a long poll for work ended
+enum Poll {
+    Work(Box<AnalysisRequest>),
+    NoWork,
+    Unauthorized,
+    /// With how long Lichess asked to wait, if it did
+    RateLimited(Option<Duration>),
+    ServerError(String),
+    /// Lichess couldn't be reached, or the connection dropped
+    ConnectionLost(String),
+    /// Any other status, e.g. 400 when Lichess doesn't accept the request
+    Rejected(StatusCode),
+}
+
 #[derive(Clone, Debug, Serialize)]
 enum EventPayloadType {
     Status,
     Uci,
+    /// Lichess answered the long poll with something unexpected, the message
+    /// is the number of seconds until the next poll (as for the ones below)
     Sleep,
+    /// The long poll ended without an analysis request, polling again now
+    NoWork,
+    /// Lichess rejected the token
+    Unauthorized,
+    RateLimited,
+    ServerError,
+    ConnectionLost,
+    /// The network is down, polling again as soon as it's back
+    Offline,
 }
 
 #[derive(Clone, Debug, Serialize)]
@@ -91,85 +136,366 @@ struct EventPayload {
 }
 
 #[derive(Clone, Debug, Serialize)]
-enum StatusLevel {
+pub enum StatusLevel {
     Info,
     Error,
 }
 
 #[derive(Clone, Debug, Serialize)]
-struct StatusPayload {
-    status: String,
-    level: StatusLevel,
+pub struct StatusPayload {
+    pub status: String,
+    pub level: StatusLevel,
 }
 
-fn send_event_to_frontend(app_handle: &AppHandle, event: &str, payload: EventPayload) {
-    println!("event: {} | {:?}", event, payload);
-    app_handle.emit_all(event, payload).unwrap();
+fn send_event_to_frontend(sink: &dyn EventSink, event: &str, payload: EventPayload) {
+    sink.send(event, payload);
 }
 
-fn send_status_to_frontend(app_handle: &AppHandle, payload: StatusPayload) {
-    app_handle
-        .emit_all("lichess::send_status_to_frontend", payload)
-        .unwrap();
+pub fn send_status_to_frontend(sink: &dyn EventSink, payload: StatusPayload) {
+    match payload.level {
+        StatusLevel::Info => info!("{}", payload.status),
+        StatusLevel::Error => warn!("{}", payload.status),
+    }
+    sink.send("lichess::send_status_to_frontend", payload);
+}
+
+/// Sets the UCI options and starts the search
+fn send_work(engine_stdin: &mut ChildStdin, work: &Work, threads: u32) -> io::Result<()> {
+    writeln!(engine_stdin, "setoption name UCI_AnalyseMode value true")?;
+    writeln!(engine_stdin, "setoption name UCI_Chess960 value true")?;
+    writeln!(engine_stdin, "setoption name Threads value {}", threads)?;
+    writeln!(engine_stdin, "setoption name Hash value {}", work.hash)?;
+    writeln!(
+        engine_stdin,
+        "setoption name MultiPV value {}",
+        work.multi_pv
+    )?;
+    writeln!(
+        engine_stdin,
+        "position fen {} moves {}",
+        work.initial_fen,
+        work.moves.join(" ")
+    )?;
+    match work.search {
+        Search::Movetime(movetime) => writeln!(engine_stdin, "go movetime {}\n", movetime)?,
+        Search::Depth(depth) => writeln!(engine_stdin, "go depth {}\n", depth)?,
+        Search::Nodes(nodes) => writeln!(engine_stdin, "go nodes {}\n", nodes)?,
+    }
+
+    engine_stdin.flush()
+}
+
+/// How long the request streaming the analysis to Lichess may stay open.
+/// Depth and node searches have no time limit but `max_search_time`.
+fn submission_timeout(search: &Search, max_search_time: Duration) -> Duration {
+    let search_time = match search {
+        Search::Movetime(movetime) => Duration::from_millis(u64::from(*movetime)),
+        Search::Depth(_) | Search::Nodes(_) => max_search_time,
+    };
+
+    search_time.min(max_search_time) + SUBMISSION_GRACE
+}
+
+/// Reads the engine's output on another thread, so the watchdog can act while
+/// the engine is silent
+fn read_lines(stdout: ChildStdout) -> Receiver<String> {
+    let (tx, rx) = mpsc::channel();
+
+    thread::spawn(move || {
+        for line in BufReader::new(stdout).lines().map_while(Result::ok) {
+            if tx.send(line).is_err() {
+                break;
+            }
+        }
+    });
+
+    rx
 }
 
-pub fn work(app_handle: &AppHandle) -> Result<(), Box<dyn Error>> {
-    let mut backoff_duration_secs = 1;
+/// Whether Lichess took the analysis, or why not
+fn submission_result(response: reqwest::Result<Response>) -> Result<(), String> {
+    match response {
+        Ok(response) if response.status().is_success() => Ok(()),
+        Ok(response) => Err(format!(
+            "Lichess did not accept the analysis ({})",
+            response.status()
+        )),
+        Err(e) if e.is_timeout() => Err("Timed out sending the analysis to Lichess".to_string()),
+        Err(e) => Err(format!("Error sending the analysis to Lichess: {}", e)),
+    }
+}
 
-    std::thread::sleep(std::time::Duration::from_secs(3));
+/// Asks the engine to quit, killing it if it doesn't in time
+fn stop_engine(engine: &mut Child) -> io::Result<ExitStatus> {
+    if let Some(mut stdin) = engine.stdin.take() {
+        // fails if the engine is already gone
+        let _ = writeln!(stdin, "quit");
+    }
 
+    let deadline = Instant::now() + ENGINE_QUIT_TIMEOUT;
     loop {
-        let api_token = db::get_setting("lichess_token");
-        match api_token {
-            Some(_) => {}
-            None => {
+        if let Some(status) = engine.try_wait()? {
+            return Ok(status);
+        }
+        if Instant::now() >= deadline {
+            engine.kill()?;
+            return engine.wait();
+        }
+        thread::sleep(Duration::from_millis(50));
+    }
+}
+
+fn build_client(settings: &Settings, api_token: &str) -> Result<Client, Box<dyn Error>> {
+    let mut default_headers = HeaderMap::new();
+    default_headers.insert(
+        header::AUTHORIZATION,
+        format!("Bearer {}", api_token).try_into()?,
+    );
+
+    Ok(http::builder_with(settings)?
+        .default_headers(default_headers)
+        .build()?)
+}
+
+/// Step 1) Long poll for analysis requests
+/// When a move is made on the Analysis board, it will be returned from this endpoint
+fn poll_for_work(client: &Client, engine_host: &str, provider_secret: String) -> Poll {
+    let response = match client
+        .post(format!("{}/api/external-engine/work", engine_host))
+        .json(&WorkRequest { provider_secret })
+        .send()
+    {
+        Ok(response) => response,
+        Err(e) => return Poll::ConnectionLost(e.to_string()),
+    };
+
+    match response.status() {
+        StatusCode::OK => match response.json::<AnalysisRequest>() {
+            Ok(analysis_request) => Poll::Work(Box::new(analysis_request)),
+            Err(e) if e.is_decode() => {
+                Poll::ServerError(format!("Invalid analysis request from Lichess: {}", e))
+            }
+            Err(e) => Poll::ConnectionLost(e.to_string()),
+        },
+        StatusCode::NO_CONTENT => Poll::NoWork,
+        StatusCode::UNAUTHORIZED => Poll::Unauthorized,
+        StatusCode::TOO_MANY_REQUESTS => Poll::RateLimited(http::retry_after(&response)),
+        status if status.is_server_error() => {
+            Poll::ServerError(format!("Lichess responded with {}", status))
+        }
+        status => Poll::Rejected(status),
+    }
+}
+
+/// Tells the frontend why the worker waits before polling again, and how long
+fn send_retry_event(sink: &dyn EventSink, event: EventPayloadType, delay: Duration) {
+    send_event_to_frontend(
+        sink,
+        "lichess::work",
+        EventPayload {
+            event,
+            message: (delay.as_secs_f64().ceil() as u64).to_string(),
+            analysis_request: None,
+        },
+    );
+}
+
+/// Sleeps until the timeout, or until the settings differ from `current`, the
+/// ones the loop went by (e.g. after logging in). Changes queued while it was
+/// busy only cut the wait short if they weren't read with `current`.
+fn wait_for_settings_change(changes: &Receiver<Settings>, current: &Settings, timeout: Duration) {
+    let deadline = Instant::now() + timeout;
+    let mut latest = changes.try_iter().last();
+
+    loop {
+        if latest.as_ref().map_or(false, |latest| latest != current) {
+            return;
+        }
+
+        match changes.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(settings) => latest = Some(settings),
+            Err(_) => return,
+        }
+    }
+}
+
+/// [`RUNNING`], even after a provider loop panicked while holding it
+fn running_accounts() -> MutexGuard<'static, HashSet<i32>> {
+    RUNNING.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// An account's entry in [`RUNNING`], removed when the provider loop ends,
+/// however it ends
+struct Running {
+    account_id: i32,
+    /// Set once the loop removed the entry itself, after which another loop
+    /// may have been started for the account
+    removed: bool,
+}
+
+impl Drop for Running {
+    fn drop(&mut self) {
+        if !self.removed {
+            running_accounts().remove(&self.account_id);
+        }
+    }
+}
+
+/// What the provider loop reads and records besides talking to Lichess (at
+/// the account's engine host) and the engine, so it can run against fakes
+trait Provider {
+    /// The account to provide analysis for, None once the loop should stop
+    fn account(&mut self) -> Option<Account>;
+    fn settings(&self) -> Settings;
+    /// Receives the settings after every change
+    fn settings_changes(&self) -> Receiver<Settings>;
+    fn engine(&self, engine_id: &str) -> Option<db::SqlEngine>;
+    fn has_engines(&self) -> bool;
+    /// Forgets the account's token after Lichess rejected it
+    fn expire_token(&mut self, sink: &dyn EventSink, reason: &str);
+    fn save_analysis(&mut self, analysis: &db::NewAnalysis);
+}
+
+/// An account of the app, with its engines in the database
+struct AppProvider {
+    running: Running,
+}
+
+impl Provider for AppProvider {
+    fn account(&mut self) -> Option<Account> {
+        let account_id = self.running.account_id;
+
+        // checked while holding the lock, so enabling the account again
+        // either sees this loop still running or starts a new one
+        let mut running_accounts = running_accounts();
+        let account = accounts::get(account_id).filter(|account| account.enabled);
+        if account.is_none() {
+            running_accounts.remove(&account_id);
+            self.running.removed = true;
+        }
+        account
+    }
+
+    fn settings(&self) -> Settings {
+        settings::get()
+    }
+
+    fn settings_changes(&self) -> Receiver<Settings> {
+        settings::subscribe()
+    }
+
+    fn engine(&self, engine_id: &str) -> Option<db::SqlEngine> {
+        db::get_engine(engine_id)
+    }
+
+    fn has_engines(&self) -> bool {
+        db::get_engine_count() > 0
+    }
+
+    fn expire_token(&mut self, sink: &dyn EventSink, reason: &str) {
+        accounts::expire_token(sink, self.running.account_id, reason);
+    }
+
+    fn save_analysis(&mut self, analysis: &db::NewAnalysis) {
+        db::add_analysis(analysis);
+    }
+}
+
+/// Starts a provider loop for every enabled account
+pub fn start_providers(sink: Arc<dyn EventSink>) -> Vec<JoinHandle<()>> {
+    accounts::all()
+        .into_iter()
+        .filter(|account| account.enabled)
+        .filter_map(|account| start_provider(sink.clone(), account.id))
+        .collect()
+}
+
+/// Starts the provider loop for an account, unless it's already running.
+/// The loop stops by itself once the account is disabled or removed.
+pub fn start_provider(sink: Arc<dyn EventSink>, account_id: i32) -> Option<JoinHandle<()>> {
+    if !running_accounts().insert(account_id) {
+        return None;
+    }
+    let mut provider = AppProvider {
+        running: Running {
+            account_id,
+            removed: false,
+        },
+    };
+
+    let sink = AccountSink {
+        inner: sink,
+        account_id,
+    };
+
+    Some(thread::spawn(move || {
+        thread::sleep(Duration::from_secs(3));
+
+        if let Err(e) = work(&sink, &mut provider) {
+            error!("Provider for account {} stopped: {}", account_id, e);
+        }
+    }))
+}
+
+fn work(sink: &dyn EventSink, provider: &mut dyn Provider) -> Result<(), Box<dyn Error>> {
+    let mut backoff = Backoff::new();
+    let settings_changes = provider.settings_changes();
+
+    loop {
+        let settings = provider.settings();
+
+        let account = match provider.account() {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+        let account_id = account.id;
+
+        let api_token = match account.lichess_token {
+            Some(api_token) => api_token,
+            None if account_id != DEFAULT_ACCOUNT => {
                 send_status_to_frontend(
-                    app_handle,
+                    sink,
                     StatusPayload {
-                        status: "Waiting for Lichess login".to_string(),
-                        level: StatusLevel::Info,
+                        status: format!(
+                            "Lichess rejected the login for {}, please add the account again with a new token",
+                            account.username.unwrap_or_default()
+                        ),
+                        level: StatusLevel::Error,
                     },
                 );
-                std::thread::sleep(std::time::Duration::from_secs(5));
+                wait_for_settings_change(&settings_changes, &settings, Duration::from_secs(60));
                 continue;
             }
-        }
-
-        let provider_secret = db::get_setting("provider_secret");
-        match provider_secret {
-            Some(_) => {}
-            None => {
+            None if login::relogin_required() => {
                 send_status_to_frontend(
-                    app_handle,
+                    sink,
                     StatusPayload {
-                        status: "Missing provider secret".to_string(),
+                        status: "Lichess login expired, please log in again".to_string(),
                         level: StatusLevel::Error,
                     },
                 );
-                std::thread::sleep(std::time::Duration::from_secs(5));
+                wait_for_settings_change(&settings_changes, &settings, Duration::from_secs(5));
                 continue;
             }
-        }
-
-        let engine_host = db::get_setting("engine_host");
-        match engine_host {
-            Some(_) => {}
             None => {
                 send_status_to_frontend(
-                    app_handle,
+                    sink,
                     StatusPayload {
-                        status: "Missing engine host setting".to_string(),
-                        level: StatusLevel::Error,
+                        status: "Waiting for Lichess login".to_string(),
+                        level: StatusLevel::Info,
                     },
                 );
-                std::thread::sleep(std::time::Duration::from_secs(5));
+                wait_for_settings_change(&settings_changes, &settings, Duration::from_secs(5));
                 continue;
             }
-        }
+        };
+
+        let provider_secret = account.provider_secret;
+        let engine_host = account.engine_host;
 
-        if db::get_engine_count() == 0 {
+        if !provider.has_engines() {
             send_status_to_frontend(
-                app_handle,
+                sink,
                 StatusPayload {
                     status: "Waiting for engine to be added".to_string(),
                     level: StatusLevel::Info,
@@ -179,19 +505,39 @@ pub fn work(app_handle: &AppHandle) -> Result<(), Box<dyn Error>> {
             continue;
         }
 
-        let mut default_headers = HeaderMap::new();
-        default_headers.insert(
-            header::AUTHORIZATION,
-            api_token.expect("missing api_token").try_into()?,
-        );
-        let client = ClientBuilder::new()
-            .default_headers(default_headers)
-            .build()?;
+        let max_threads = match resource_policy::check(&settings) {
+            Decision::Run { max_threads } => max_threads,
+            Decision::Pause(reason) => {
+                send_status_to_frontend(
+                    sink,
+                    StatusPayload {
+                        status: reason,
+                        level: StatusLevel::Info,
+                    },
+                );
+                wait_for_settings_change(&settings_changes, &settings, RESOURCE_RECHECK_INTERVAL);
+                continue;
+            }
+        };
+
+        let client = match build_client(&settings, &api_token) {
+            Ok(client) => client,
+            Err(e) => {
+                send_status_to_frontend(
+                    sink,
+                    StatusPayload {
+                        status: format!("Error in the network settings: {}", e),
+                        level: StatusLevel::Error,
+                    },
+                );
+                wait_for_settings_change(&settings_changes, &settings, Duration::from_secs(60));
+                continue;
+            }
+        };
+        let max_backoff = Duration::from_secs(settings.max_backoff_secs);
 
-        // Step 1) Long poll for analysis requests
-        // When a move is made on the Analysis board, it will be returned from this endpoint
         send_event_to_frontend(
-            app_handle,
+            sink,
             "lichess::work",
             EventPayload {
                 event: EventPayloadType::Status,
@@ -199,38 +545,107 @@ pub fn work(app_handle: &AppHandle) -> Result<(), Box<dyn Error>> {
                 analysis_request: None,
             },
         );
-        let response = client
-            .post(format!(
-                "{}/api/external-engine/work",
-                engine_host.clone().expect("missing engine_host")
-            ))
-            .json(&WorkRequest {
-                provider_secret: provider_secret.expect("missing provider_secret"),
-            })
-            .send()?;
-
-        if response.status() != 200 {
-            send_event_to_frontend(
-                app_handle,
-                "lichess::work",
-                EventPayload {
-                    event: EventPayloadType::Sleep,
-                    message: backoff_duration_secs.to_string(),
-                    analysis_request: None,
-                },
-            );
-
-            std::thread::sleep(std::time::Duration::from_secs(backoff_duration_secs));
-            backoff_duration_secs = std::cmp::min(backoff_duration_secs * 2, 10);
-            continue;
-        }
-
-        backoff_duration_secs = 1;
-
-        let analysis_request = response.json::<AnalysisRequest>()?;
+        let analysis_request = match poll_for_work(&client, &engine_host, provider_secret) {
+            Poll::Work(analysis_request) => {
+                backoff.reset();
+                *analysis_request
+            }
+            Poll::NoWork => {
+                backoff.reset();
+                send_event_to_frontend(
+                    sink,
+                    "lichess::work",
+                    EventPayload {
+                        event: EventPayloadType::NoWork,
+                        message: "No analysis requested".to_string(),
+                        analysis_request: None,
+                    },
+                );
+                continue;
+            }
+            Poll::Unauthorized => {
+                warn!("Lichess rejected the token of account {}", account_id);
+                send_event_to_frontend(
+                    sink,
+                    "lichess::work",
+                    EventPayload {
+                        event: EventPayloadType::Unauthorized,
+                        message: "Lichess rejected the login".to_string(),
+                        analysis_request: None,
+                    },
+                );
+                provider.expire_token(sink, "Lichess rejected the login, please log in again");
+                continue;
+            }
+            Poll::RateLimited(retry_after) => {
+                let delay = retry_after
+                    .unwrap_or_else(|| backoff.next_delay(max_backoff))
+                    .min(max_backoff.max(MAX_RATE_LIMIT_WAIT));
+                warn!(
+                    "Lichess rate limited account {}, polling again in {:.1}s",
+                    account_id,
+                    delay.as_secs_f64()
+                );
+                send_retry_event(sink, EventPayloadType::RateLimited, delay);
+                // still cut short by settings changes, so disabling the account
+                // or logging out doesn't wait for Lichess
+                wait_for_settings_change(&settings_changes, &settings, delay);
+                continue;
+            }
+            Poll::ServerError(e) => {
+                let delay = backoff.next_delay(max_backoff);
+                warn!("{}, polling again in {:.1}s", e, delay.as_secs_f64());
+                send_retry_event(sink, EventPayloadType::ServerError, delay);
+                wait_for_settings_change(&settings_changes, &settings, delay);
+                continue;
+            }
+            Poll::ConnectionLost(e) if !connectivity::is_online(&engine_host) => {
+                warn!("Offline ({}), waiting for the network", e);
+                send_event_to_frontend(
+                    sink,
+                    "lichess::work",
+                    EventPayload {
+                        event: EventPayloadType::Offline,
+                        message: "Offline".to_string(),
+                        analysis_request: None,
+                    },
+                );
+                connectivity::wait_until_online(&engine_host, &settings_changes);
+                info!("Back online, polling for work");
+                // the failures were the network's, not Lichess'
+                backoff.reset();
+                continue;
+            }
+            Poll::ConnectionLost(e) => {
+                let delay = backoff.next_delay(max_backoff);
+                warn!(
+                    "Error polling for work: {}, polling again in {:.1}s",
+                    e,
+                    delay.as_secs_f64()
+                );
+                send_retry_event(sink, EventPayloadType::ConnectionLost, delay);
+                wait_for_settings_change(&settings_changes, &settings, delay);
+                continue;
+            }
+            Poll::Rejected(status) => {
+                let delay = backoff.next_delay(max_backoff);
+                debug!(
+                    "Long poll returned {}, polling again in {:.1}s",
+                    status,
+                    delay.as_secs_f64()
+                );
+                send_retry_event(sink, EventPayloadType::Sleep, delay);
+                wait_for_settings_change(&settings_changes, &settings, delay);
+                continue;
+            }
+        };
+        info!(
+            "Analyzing {} with {} ({:?})",
+            analysis_request.id, analysis_request.engine.name, analysis_request.work.search
+        );
 
         send_event_to_frontend(
-            app_handle,
+            sink,
             "lichess::work",
             EventPayload {
                 event: EventPayloadType::Status,
@@ -239,12 +654,11 @@ pub fn work(app_handle: &AppHandle) -> Result<(), Box<dyn Error>> {
             },
         );
 
-        let binary_filepath = db::get_engine_binary_path(&analysis_request.engine.id);
-        match binary_filepath {
-            Some(_) => {}
+        let local_engine = match provider.engine(&analysis_request.engine.id) {
+            Some(local_engine) => local_engine,
             None => {
                 send_status_to_frontend(
-                    app_handle,
+                    sink,
                     StatusPayload {
                         status: "Missing binary filepath".to_string(),
                         level: StatusLevel::Error,
@@ -253,33 +667,48 @@ pub fn work(app_handle: &AppHandle) -> Result<(), Box<dyn Error>> {
                 std::thread::sleep(std::time::Duration::from_secs(5));
                 continue;
             }
-        }
+        };
 
         // Step 2) Send the FEN to the engine
-        let mut process = Command::new(binary_filepath.as_ref().expect("missing binary_filepath"));
-        process.stdin(Stdio::piped()).stdout(Stdio::piped());
+        let mut process = Command::new(&local_engine.binary_location);
+        process
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
 
-        // Hide the console window on Windows
-        #[cfg(windows)]
-        if cfg!(windows) {
-            use std::os::windows::process::CommandExt;
-
-            const CREATE_NO_WINDOW: u32 = 0x08000000;
-            process.creation_flags(CREATE_NO_WINDOW);
-        }
+        let options = local_engine.options();
+        let placement = Placement::new(&options, &settings);
 
-        let mut engine = process.spawn();
+        // kept until the engine is spawned
+        let _sandbox = match sandbox::configure(
+            &mut process,
+            &local_engine.binary_location,
+            &options,
+            &placement,
+        ) {
+            Ok(sandbox) => sandbox,
+            Err(e) => {
+                send_status_to_frontend(
+                    sink,
+                    StatusPayload {
+                        status: format!("Failed to start engine: {}", e),
+                        level: StatusLevel::Error,
+                    },
+                );
+                std::thread::sleep(std::time::Duration::from_secs(5));
+                continue;
+            }
+        };
 
-        match engine {
-            Ok(_) => {}
+        let mut engine = match process.spawn() {
+            Ok(engine) => engine,
             Err(e) => {
                 send_status_to_frontend(
-                    app_handle,
+                    sink,
                     StatusPayload {
                         status: format!(
                             "Failed to start engine: {} for {}",
-                            e,
-                            binary_filepath.as_ref().unwrap()
+                            e, local_engine.binary_location
                         ),
                         level: StatusLevel::Error,
                     },
@@ -287,65 +716,97 @@ pub fn work(app_handle: &AppHandle) -> Result<(), Box<dyn Error>> {
                 std::thread::sleep(std::time::Duration::from_secs(5));
                 continue;
             }
-        }
+        };
+        // counted until the end of the iteration, after the engine was stopped
+        let _analysing = resource_policy::Analysing::start();
 
-        let engine_stdin = engine.as_mut().unwrap().stdin.as_mut().unwrap();
-
-        // Set UCI options
-        writeln!(engine_stdin, "setoption name UCI_AnalyseMode value true")?;
-        writeln!(engine_stdin, "setoption name UCI_Chess960 value true")?;
-        writeln!(
-            engine_stdin,
-            "setoption name Threads value {}",
-            analysis_request.work.threads
-        )?;
-        writeln!(
-            engine_stdin,
-            "setoption name Hash value {}",
-            analysis_request.work.hash
-        )?;
-        writeln!(
-            engine_stdin,
-            "setoption name MultiPV value {}",
-            analysis_request.work.multi_pv
-        )?;
-        writeln!(
-            engine_stdin,
-            "position fen {} moves {}",
-            analysis_request.work.initial_fen,
-            analysis_request.work.moves.join(" ")
-        )?;
-        match analysis_request.work.search {
-            Search::Movetime(movetime) => writeln!(engine_stdin, "go movetime {}\n", movetime)?,
-            Search::Depth(depth) => writeln!(engine_stdin, "go depth {}\n", depth)?,
-            Search::Nodes(nodes) => writeln!(engine_stdin, "go nodes {}\n", nodes)?,
+        let engine_log = EngineLog::start(
+            &local_engine.engine_id,
+            &local_engine.binary_location,
+            engine.stderr.take(),
+        );
+
+        let engine_stdin = engine.stdin.as_mut().unwrap();
+
+        // the machine may be too busy for as many threads as Lichess asks for
+        let mut threads = match max_threads {
+            Some(max_threads) => analysis_request.work.threads.min(max_threads),
+            None => analysis_request.work.threads,
+        };
+        // more threads than pinned cores only makes them take turns
+        if let Some(cpus) = &placement.cpus {
+            threads = threads.min(cpus.len() as u32);
         }
 
-        engine_stdin.flush()?;
+        if let Err(e) = send_work(engine_stdin, &analysis_request.work, threads) {
+            // the engine died on startup, its exit is reported below
+            warn!("Error sending work to the engine: {}", e);
+        }
 
-        let engine_stdout = engine.as_mut().unwrap().stdout.as_mut().unwrap();
+        let engine_lines = read_lines(engine.stdout.take().unwrap());
 
-        let (tx, rx) = std::sync::mpsc::channel();
+        let (tx, rx) = mpsc::channel();
         let client = client.clone();
+        let url = format!(
+            "{}/api/external-engine/work/{}",
+            engine_host, analysis_request.id
+        );
 
-        std::thread::spawn(move || {
+        let max_search_time = Duration::from_secs(settings.max_search_secs);
+        let timeout = submission_timeout(&analysis_request.work.search, max_search_time);
+
+        let submission = thread::spawn(move || {
             // Step 3) Start a POST request stream to /api/external-engine/work/{id}
-            let url = format!(
-                "{}/api/external-engine/work/{}",
-                engine_host.expect("missing engine_host"),
-                analysis_request.id
-            );
-            client
-                .post(url)
-                .body(Body::new(iter_read::IterRead::new(rx.into_iter().fuse())))
-                .timeout(Duration::from_secs(600))
-                .send()
+            submission_result(
+                client
+                    .post(url)
+                    .body(Body::new(iter_read::IterRead::new(rx.into_iter().fuse())))
+                    .timeout(timeout)
+                    .send(),
+            )
         });
 
-        for line in BufReader::new(engine_stdout).lines() {
-            let mut line = line?;
+        // Latest complete line for each PV, kept for the analysis history
+        let mut lines: BTreeMap<u32, uci::Info> = BTreeMap::new();
+
+        // whether the engine got to the end of its search, or was told to stop
+        let mut finished = false;
+        // whether the engine ignored `stop` from the watchdog
+        let mut unresponsive = false;
+
+        let mut watchdog = Watchdog::start(&analysis_request.work.search, max_search_time);
+
+        loop {
+            let line = match engine_lines.recv_timeout(WATCHDOG_INTERVAL) {
+                Ok(line) => Some(line),
+                Err(RecvTimeoutError::Timeout) => None,
+                // stdout closed
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+
+            match watchdog.check() {
+                Action::Continue => {}
+                Action::Stop(reason) => {
+                    warn!("Stopping {}, it {}", analysis_request.engine.name, reason);
+                    if let Some(stdin) = engine.stdin.as_mut() {
+                        // a dead engine is noticed once stdout closes
+                        let _ = writeln!(stdin, "stop").and_then(|_| stdin.flush());
+                    }
+                }
+                Action::Kill => {
+                    unresponsive = true;
+                    break;
+                }
+            }
+
+            let mut line = match line {
+                Some(line) => line,
+                None => continue,
+            };
+
+            engine_log.output(&line);
             send_event_to_frontend(
-                app_handle,
+                sink,
                 "lichess::work",
                 EventPayload {
                     event: EventPayloadType::Uci,
@@ -354,14 +815,450 @@ pub fn work(app_handle: &AppHandle) -> Result<(), Box<dyn Error>> {
                 },
             );
             if line.starts_with("info") {
+                if let Some(info) = uci::Info::parse(&line) {
+                    watchdog.update(&info);
+                    if info.is_complete_line() {
+                        lines.insert(info.multi_pv.unwrap_or(1), info);
+                    }
+                }
+
                 line.push('\n');
                 if tx.send(line).is_err() {
                     // sending thread stopped, meaning Lichess doesn't want any more analysis. wait for next request
+                    finished = true;
                     break;
                 }
             } else if line.starts_with("bestmove") {
+                finished = true;
                 break;
             }
         }
+
+        // stdout closing before bestmove means the engine died
+        let status = stop_engine(&mut engine);
+        let crash = match &status {
+            _ if finished || unresponsive => None,
+            Ok(status) => Some(engine_log::describe(status)),
+            Err(_) => Some("unknown status".to_string()),
+        };
+        let output = engine_log.finish(status.map_err(|e| e.to_string()));
+
+        if unresponsive {
+            send_status_to_frontend(
+                sink,
+                StatusPayload {
+                    status: format!(
+                        "{} did not stop searching when asked to and was killed",
+                        analysis_request.engine.name
+                    ),
+                    level: StatusLevel::Error,
+                },
+            );
+        }
+
+        if let Some(crash) = crash {
+            let last_lines = output[output.len().saturating_sub(CRASH_REPORT_LINES)..].join("\n");
+            send_status_to_frontend(
+                sink,
+                StatusPayload {
+                    status: format!(
+                        "{} crashed ({}), see its log for details\n{}",
+                        analysis_request.engine.name, crash, last_lines
+                    ),
+                    level: StatusLevel::Error,
+                },
+            );
+        }
+
+        // ends the request body, if Lichess is still reading it
+        drop(tx);
+        let submission_error = match submission.join() {
+            Ok(result) => result.err(),
+            Err(_) => Some("Sending the analysis to Lichess failed unexpectedly".to_string()),
+        };
+
+        if let Some(e) = &submission_error {
+            send_status_to_frontend(
+                sink,
+                StatusPayload {
+                    status: format!("{} ({})", e, analysis_request.id),
+                    level: StatusLevel::Error,
+                },
+            );
+        }
+
+        // failed requests are recorded even without any lines
+        if !lines.is_empty() || submission_error.is_some() {
+            let work = &analysis_request.work;
+            provider.save_analysis(&db::NewAnalysis {
+                id: &analysis_request.id,
+                session_id: &work.session_id,
+                engine_id: &analysis_request.engine.id,
+                engine_name: &analysis_request.engine.name,
+                variant: &work.variant,
+                initial_fen: &work.initial_fen,
+                moves: &work.moves.join(" "),
+                lines: &serde_json::to_string(&lines.into_values().collect::<Vec<_>>())?,
+                submission_error: submission_error.as_deref(),
+            });
+        }
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{
+        env, fs,
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        os::unix::fs::PermissionsExt,
+        path::PathBuf,
+        process,
+        sync::{
+            mpsc::{self, Receiver, Sender},
+            Arc, Mutex,
+        },
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use serde_json::{json, Value};
+
+    use super::{wait_for_settings_change, work, Provider};
+    use crate::{
+        accounts::Account,
+        db,
+        events::{ChannelSink, EventSink, NoopSink},
+        settings::Settings,
+    };
+
+    /// How long the provider loop may take before the test fails
+    const TEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+    /// Answers `go` with one line of analysis and a best move
+    const FAKE_ENGINE: &str = "#!/bin/sh
+while read -r line; do
+  case \"$line\" in
+    go*)
+      echo 'info depth 1 multipv 1 score cp 20 nodes 20 nps 2000 time 10 pv e2e4'
+      echo 'bestmove e2e4'
+      ;;
+    quit) exit 0 ;;
+  esac
+done
+";
+
+    #[derive(Debug)]
+    struct Request {
+        path: String,
+        body: String,
+    }
+
+    /// Serves the external engine endpoints on a local port, answering each
+    /// request with the status and JSON body `respond` returns
+    fn fake_lichess(respond: fn(&Request) -> (u16, String)) -> (String, Arc<Mutex<Vec<Request>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let requests = Arc::new(Mutex::new(Vec::new()));
+
+        let received = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let received = received.clone();
+                thread::spawn(move || serve(stream, respond, &received));
+            }
+        });
+
+        (url, requests)
+    }
+
+    fn serve(
+        stream: TcpStream,
+        respond: fn(&Request) -> (u16, String),
+        received: &Mutex<Vec<Request>>,
+    ) {
+        let mut writer = stream.try_clone().unwrap();
+        let mut reader = BufReader::new(stream);
+
+        // the client keeps the connection open for further requests
+        while let Some(request) = read_request(&mut reader) {
+            let (status, body) = respond(&request);
+            received.lock().unwrap().push(request);
+
+            let response = format!(
+                "HTTP/1.1 {} Fake\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            if writer.write_all(response.as_bytes()).is_err() {
+                break;
+            }
+        }
+    }
+
+    fn read_request(reader: &mut impl BufRead) -> Option<Request> {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line).ok()? == 0 {
+            return None;
+        }
+        let path = request_line.split_whitespace().nth(1)?.to_string();
+
+        let mut content_length = 0;
+        let mut chunked = false;
+        loop {
+            let mut header = String::new();
+            reader.read_line(&mut header).ok()?;
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            let (name, value) = header.split_once(':')?;
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().ok()?,
+                "transfer-encoding" => chunked = value.trim() == "chunked",
+                _ => {}
+            }
+        }
+
+        // the analysis is streamed in chunks
+        let mut body = Vec::new();
+        if chunked {
+            loop {
+                let mut size = String::new();
+                reader.read_line(&mut size).ok()?;
+                let size = usize::from_str_radix(size.trim(), 16).ok()?;
+                // followed by CRLF, also after the last, empty chunk
+                let mut chunk = vec![0; size + 2];
+                reader.read_exact(&mut chunk).ok()?;
+                if size == 0 {
+                    break;
+                }
+                body.extend_from_slice(&chunk[..size]);
+            }
+        } else {
+            body.resize(content_length, 0);
+            reader.read_exact(&mut body).ok()?;
+        }
+
+        Some(Request {
+            path,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+
+    fn analysis_request() -> Value {
+        json!({
+            "id": "request1",
+            "work": {
+                "sessionId": "session1",
+                "threads": 1,
+                "hash": 16,
+                "movetime": 100,
+                "multiPv": 1,
+                "variant": "chess",
+                "initialFen": "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1",
+                "moves": [],
+            },
+            "engine": {
+                "id": "engine1",
+                "name": "Fake engine",
+                "clientSecret": "client secret",
+                "userId": "user1",
+                "maxThreads": 1,
+                "maxHash": 16,
+                "variants": ["chess"],
+                "providerData": null,
+            },
+        })
+    }
+
+    /// A temporary directory for a test, also used as the app data dir so the
+    /// engine logs don't end up in the real one
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("lichess-tauri-test-{}", process::id()));
+        env::set_var("XDG_DATA_HOME", &dir);
+        env::set_var("HOME", &dir);
+
+        let dir = dir.join(name);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    struct SavedAnalysis {
+        id: String,
+        lines: Vec<Value>,
+        submission_error: Option<String>,
+    }
+
+    /// Provides for one account until it was told to stop: once it recorded
+    /// an analysis or its token expired
+    struct FakeProvider {
+        account: Account,
+        binary_location: String,
+        settings_changes: Mutex<Vec<Sender<Settings>>>,
+        analyses: Vec<SavedAnalysis>,
+        expired: Option<String>,
+    }
+
+    impl FakeProvider {
+        fn new(engine_host: &str, binary_location: &str) -> Self {
+            FakeProvider {
+                account: Account {
+                    id: 1,
+                    lichess_host: engine_host.to_string(),
+                    engine_host: engine_host.to_string(),
+                    username: Some("user1".to_string()),
+                    lichess_token: Some("lip_token".to_string()),
+                    provider_secret: "provider secret".to_string(),
+                    token_expires_at: None,
+                    enabled: true,
+                },
+                binary_location: binary_location.to_string(),
+                settings_changes: Mutex::new(Vec::new()),
+                analyses: Vec::new(),
+                expired: None,
+            }
+        }
+
+        /// Runs the provider loop on another thread until it returns
+        fn run(self, sink: impl EventSink + 'static) -> (Result<(), String>, FakeProvider) {
+            let (tx, rx) = mpsc::channel();
+            thread::spawn(move || {
+                let mut provider = self;
+                let result = work(&sink, &mut provider).map_err(|e| e.to_string());
+                let _ = tx.send((result, provider));
+            });
+
+            rx.recv_timeout(TEST_TIMEOUT)
+                .expect("The provider loop didn't stop")
+        }
+    }
+
+    impl Provider for FakeProvider {
+        fn account(&mut self) -> Option<Account> {
+            if !self.analyses.is_empty() || self.expired.is_some() {
+                return None;
+            }
+            Some(self.account.clone())
+        }
+
+        fn settings(&self) -> Settings {
+            Settings::default()
+        }
+
+        fn settings_changes(&self) -> Receiver<Settings> {
+            let (tx, rx) = mpsc::channel();
+            self.settings_changes.lock().unwrap().push(tx);
+            rx
+        }
+
+        fn engine(&self, engine_id: &str) -> Option<db::SqlEngine> {
+            Some(db::SqlEngine {
+                engine_id: engine_id.to_string(),
+                binary_location: self.binary_location.clone(),
+                sandbox: false,
+                memory_limit_mb: None,
+                cpu_time_limit_secs: None,
+                allowed_paths: String::new(),
+                nice: None,
+                cpus: None,
+                free_cores: None,
+            })
+        }
+
+        fn has_engines(&self) -> bool {
+            true
+        }
+
+        fn expire_token(&mut self, _sink: &dyn EventSink, reason: &str) {
+            self.expired = Some(reason.to_string());
+        }
+
+        fn save_analysis(&mut self, analysis: &db::NewAnalysis) {
+            self.analyses.push(SavedAnalysis {
+                id: analysis.id.to_string(),
+                lines: serde_json::from_str(analysis.lines).unwrap(),
+                submission_error: analysis.submission_error.map(String::from),
+            });
+        }
+    }
+
+    #[test]
+    fn streams_the_analysis_of_a_request() {
+        let dir = test_dir("streams_the_analysis_of_a_request");
+        let engine = dir.join("engine.sh");
+        fs::write(&engine, FAKE_ENGINE).unwrap();
+        fs::set_permissions(&engine, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let (url, requests) = fake_lichess(|request| match request.path.as_str() {
+            "/api/external-engine/work" => (200, analysis_request().to_string()),
+            _ => (204, String::new()),
+        });
+
+        let (sink, events) = ChannelSink::new();
+        let (result, provider) = FakeProvider::new(&url, engine.to_str().unwrap()).run(sink);
+        assert_eq!(result, Ok(()));
+
+        let requests = requests.lock().unwrap();
+        assert_eq!(requests[0].path, "/api/external-engine/work");
+        assert!(requests[0]
+            .body
+            .contains(r#""providerSecret":"provider secret""#));
+        assert_eq!(requests[1].path, "/api/external-engine/work/request1");
+        assert!(requests[1]
+            .body
+            .starts_with("info depth 1 multipv 1 score cp 20"));
+
+        assert_eq!(provider.analyses.len(), 1);
+        let analysis = &provider.analyses[0];
+        assert_eq!(analysis.id, "request1");
+        assert_eq!(analysis.submission_error, None);
+        assert_eq!(analysis.lines.len(), 1);
+        assert_eq!(analysis.lines[0]["pv"], json!(["e2e4"]));
+
+        let uci_output: Vec<Value> = events
+            .try_iter()
+            .filter(|(_, payload)| payload["event"] == "Uci")
+            .map(|(_, payload)| payload["message"].clone())
+            .collect();
+        assert_eq!(uci_output.last(), Some(&json!("bestmove e2e4")));
+    }
+
+    #[test]
+    fn expires_a_rejected_token() {
+        let (url, requests) = fake_lichess(|_| (401, String::new()));
+
+        let (result, provider) = FakeProvider::new(&url, "/nonexistent").run(NoopSink);
+        assert_eq!(result, Ok(()));
+
+        assert_eq!(requests.lock().unwrap().len(), 1);
+        assert_eq!(
+            provider.expired.as_deref(),
+            Some("Lichess rejected the login, please log in again")
+        );
+        assert!(provider.analyses.is_empty());
+    }
+
+    #[test]
+    fn waits_out_settings_changes_already_read() {
+        let (tx, rx) = mpsc::channel();
+        let current = Settings::default();
+
+        // e.g. the updates of a logout, read before the wait
+        tx.send(current.clone()).unwrap();
+        tx.send(current.clone()).unwrap();
+        let started = Instant::now();
+        wait_for_settings_change(&rx, &current, Duration::from_millis(200));
+        assert!(started.elapsed() >= Duration::from_millis(200));
+
+        let changed = Settings {
+            provider_enabled: false,
+            ..current.clone()
+        };
+        tx.send(changed).unwrap();
+        let started = Instant::now();
+        wait_for_settings_change(&rx, &current, TEST_TIMEOUT);
+        assert!(started.elapsed() < TEST_TIMEOUT);
     }
 }
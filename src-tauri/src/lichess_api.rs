@@ -0,0 +1,206 @@
+//! Client for the external engine endpoints of the Lichess API.
+//!
+//! Engines are registered on Lichess and linked to a local binary in the
+//! `engines` table. Going through here keeps both sides in step.
+
+use std::{collections::HashMap, error::Error, fmt};
+
+use reqwest::{
+    blocking::{Client, RequestBuilder, Response},
+    StatusCode,
+};
+use serde::{Deserialize, Serialize, Serializer};
+
+use crate::{accounts::Account, http};
+
+/// An external engine as Lichess returns it
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExternalEngine {
+    pub id: String,
+    pub name: String,
+    pub client_secret: String,
+    pub user_id: String,
+    pub max_threads: u32,
+    pub max_hash: u32,
+    pub variants: Vec<String>,
+    pub provider_data: Option<String>,
+}
+
+impl ExternalEngine {
+    /// Registration that keeps the engine as it is, with the given secret
+    pub fn registration(&self, provider_secret: &str) -> Registration {
+        Registration {
+            name: self.name.clone(),
+            max_threads: self.max_threads,
+            max_hash: self.max_hash,
+            variants: self.variants.clone(),
+            provider_secret: provider_secret.to_string(),
+            provider_data: self.provider_data.clone(),
+        }
+    }
+}
+
+/// The engine settings the user picks, as sent by the engine form
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NewEngine {
+    pub name: String,
+    pub max_threads: u32,
+    pub max_hash: u32,
+    pub variants: Vec<String>,
+}
+
+impl NewEngine {
+    pub fn registration(self, provider_secret: &str) -> Registration {
+        Registration {
+            name: self.name,
+            max_threads: self.max_threads,
+            max_hash: self.max_hash,
+            variants: self.variants,
+            provider_secret: provider_secret.to_string(),
+            provider_data: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Registration {
+    pub name: String,
+    pub max_threads: u32,
+    pub max_hash: u32,
+    pub variants: Vec<String>,
+    pub provider_secret: String,
+    /// Sent back unchanged, Lichess would otherwise clear it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub provider_data: Option<String>,
+}
+
+#[derive(Debug)]
+pub enum ApiError {
+    NotLoggedIn,
+    /// Lichess rejected the token
+    Unauthorized,
+    /// Validation errors by field, shown next to the form inputs
+    Invalid(HashMap<String, Vec<String>>),
+    NotFound,
+    Status(StatusCode),
+    Request(reqwest::Error),
+    /// The HTTP client couldn't be set up from the network settings
+    Client(String),
+    /// Saving the result locally failed
+    Storage(String),
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ApiError::NotLoggedIn => write!(f, "Not logged in to Lichess"),
+            ApiError::Unauthorized => write!(f, "Lichess rejected the login"),
+            ApiError::Invalid(errors) => {
+                let mut messages: Vec<String> = errors
+                    .iter()
+                    .map(|(field, errors)| format!("{}: {}", field, errors.join(", ")))
+                    .collect();
+                messages.sort();
+                write!(f, "Invalid engine: {}", messages.join("; "))
+            }
+            ApiError::NotFound => write!(f, "The engine does not exist on Lichess"),
+            ApiError::Status(status) => write!(f, "Lichess responded with {}", status),
+            ApiError::Request(e) => write!(f, "Error contacting Lichess: {}", e),
+            ApiError::Client(e) => write!(f, "{}", e),
+            ApiError::Storage(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl Error for ApiError {}
+
+impl From<reqwest::Error> for ApiError {
+    fn from(e: reqwest::Error) -> Self {
+        ApiError::Request(e)
+    }
+}
+
+/// Serialized as errors by field, like Lichess' validation errors, so the
+/// engine form can show any error the same way
+impl Serialize for ApiError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            ApiError::Invalid(errors) => errors.serialize(serializer),
+            _ => HashMap::from([("error", vec![self.to_string()])]).serialize(serializer),
+        }
+    }
+}
+
+pub struct LichessApi {
+    client: Client,
+    lichess_host: String,
+    api_token: String,
+}
+
+impl LichessApi {
+    pub fn new(lichess_host: &str, api_token: &str) -> Result<Self, ApiError> {
+        Ok(LichessApi {
+            client: http::client().map_err(ApiError::Client)?,
+            lichess_host: lichess_host.to_string(),
+            api_token: api_token.to_string(),
+        })
+    }
+
+    pub fn for_account(account: &Account) -> Result<Self, ApiError> {
+        match &account.lichess_token {
+            Some(api_token) => LichessApi::new(&account.lichess_host, api_token),
+            None => Err(ApiError::NotLoggedIn),
+        }
+    }
+
+    fn url(&self, path: &str) -> String {
+        format!("{}/api/external-engine{}", self.lichess_host, path)
+    }
+
+    fn send(&self, request: RequestBuilder) -> Result<Response, ApiError> {
+        let response = request.bearer_auth(&self.api_token).send()?;
+
+        match response.status() {
+            status if status.is_success() => Ok(response),
+            StatusCode::UNAUTHORIZED => Err(ApiError::Unauthorized),
+            StatusCode::NOT_FOUND => Err(ApiError::NotFound),
+            StatusCode::BAD_REQUEST => match response.json() {
+                Ok(errors) => Err(ApiError::Invalid(errors)),
+                Err(_) => Err(ApiError::Status(StatusCode::BAD_REQUEST)),
+            },
+            status => Err(ApiError::Status(status)),
+        }
+    }
+
+    pub fn list_engines(&self) -> Result<Vec<ExternalEngine>, ApiError> {
+        Ok(self.send(self.client.get(self.url("")))?.json()?)
+    }
+
+    pub fn register_engine(&self, registration: &Registration) -> Result<ExternalEngine, ApiError> {
+        Ok(self
+            .send(self.client.post(self.url("")).json(registration))?
+            .json()?)
+    }
+
+    pub fn update_engine(
+        &self,
+        engine_id: &str,
+        registration: &Registration,
+    ) -> Result<ExternalEngine, ApiError> {
+        Ok(self
+            .send(
+                self.client
+                    .put(self.url(&format!("/{}", engine_id)))
+                    .json(registration),
+            )?
+            .json()?)
+    }
+
+    pub fn delete_engine(&self, engine_id: &str) -> Result<(), ApiError> {
+        self.send(self.client.delete(self.url(&format!("/{}", engine_id))))?;
+        Ok(())
+    }
+}
@@ -0,0 +1,396 @@
+//! Application logs, written to `logs` in the app data dir (rotated daily)
+//! and to stdout.
+//!
+//! Release builds on desktop have no terminal, so anything only printed is
+//! lost. Every line goes through [`redact`] first, so tokens and secrets that
+//! end up in a message (request headers, engine registrations, ...) don't
+//! reach the files users attach to bug reports.
+
+use std::{
+    error::Error,
+    fs::{self, File},
+    io::{self, Write},
+    panic,
+    path::{Path, PathBuf},
+    thread,
+};
+
+use once_cell::sync::OnceCell;
+use serde_json::{json, Value};
+use tracing_appender::{
+    non_blocking::WorkerGuard,
+    rolling::{self, Rotation},
+};
+use tracing_subscriber::{filter::LevelFilter, fmt::MakeWriter, prelude::*, reload, Registry};
+use zip::{write::FileOptions, ZipWriter};
+
+use crate::{db, engine_log, settings, utils::get_app_data_dir};
+
+const LOG_FILE_PREFIX: &str = "lichess-tauri";
+/// Days of logs kept
+const MAX_LOG_FILES: usize = 7;
+
+const REDACTED: &str = "[redacted]";
+
+/// Keys whose values never make it into the logs, lowercase
+const SECRET_KEYS: [&str; 7] = [
+    "authorization",
+    "provider_secret",
+    "providersecret",
+    "client_secret",
+    "clientsecret",
+    "lichess_token",
+    "access_token",
+];
+
+/// Prefixes of Lichess personal (`lip_`) and OAuth (`lio_`) tokens, redacted
+/// wherever they appear
+const TOKEN_PREFIXES: [&str; 2] = ["lip_", "lio_"];
+
+static LEVEL: OnceCell<reload::Handle<LevelFilter, Registry>> = OnceCell::new();
+/// Flushes the file writer when dropped, so it lives as long as the app
+static FILE_WRITER: OnceCell<WorkerGuard> = OnceCell::new();
+
+pub fn logs_dir() -> PathBuf {
+    get_app_data_dir().join("logs")
+}
+
+/// Starts logging at the `info` level. Called first thing, before the
+/// settings can be read; [`follow_settings`] applies the configured level.
+pub fn init() {
+    let (level, handle) = reload::Layer::new(LevelFilter::INFO);
+
+    let file_layer = match rolling::Builder::new()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix("log")
+        .max_log_files(MAX_LOG_FILES)
+        .build(logs_dir())
+    {
+        Ok(appender) => {
+            let (writer, guard) = tracing_appender::non_blocking(appender);
+            let _ = FILE_WRITER.set(guard);
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_ansi(false)
+                    .with_writer(Redacting(writer)),
+            )
+        }
+        Err(e) => {
+            eprintln!(
+                "Error opening the log directory, logging to stdout only: {}",
+                e
+            );
+            None
+        }
+    };
+
+    tracing_subscriber::registry()
+        .with(level)
+        .with(file_layer)
+        .with(tracing_subscriber::fmt::layer().with_writer(Redacting(io::stdout)))
+        .init();
+
+    let _ = LEVEL.set(handle);
+
+    // a failed query panics, which would otherwise only reach stderr
+    let default_hook = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        tracing::error!("{}", info);
+        default_hook(info);
+    }));
+}
+
+fn set_level(level: &str) {
+    let level = match level.parse::<LevelFilter>() {
+        Ok(level) => level,
+        Err(_) => return,
+    };
+
+    if let Some(handle) = LEVEL.get() {
+        if let Err(e) = handle.modify(|filter| *filter = level) {
+            tracing::error!("Error changing the log level: {}", e);
+        }
+    }
+}
+
+/// Applies the `log_level` setting, now and whenever it changes
+pub fn follow_settings() {
+    let changes = settings::subscribe();
+    set_level(&settings::get().log_level);
+
+    thread::spawn(move || {
+        for settings in changes {
+            set_level(&settings.log_level);
+        }
+    });
+}
+
+/// Blanks out the values of secret keys (`Authorization: Bearer ...`,
+/// `"providerSecret":"..."`, `client_secret=...`) and Lichess tokens
+pub fn redact(text: &str) -> String {
+    let lower = text.to_ascii_lowercase();
+    let bytes = text.as_bytes();
+
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut position = 0;
+
+    loop {
+        let next_key = SECRET_KEYS
+            .iter()
+            .filter_map(|key| find_word(&lower, key, position).map(|at| (at, key.len())))
+            .min();
+        let (key_start, key_len) = match next_key {
+            Some(next_key) => next_key,
+            None => break,
+        };
+
+        let mut end = key_start + key_len;
+        while end < bytes.len() && matches!(bytes[end], b'"' | b'\'' | b':' | b'=' | b' ') {
+            end += 1;
+        }
+        if lower[end..].starts_with("bearer ") {
+            end += "bearer ".len();
+        }
+
+        let value_start = end;
+        while end < bytes.len() && !is_value_end(bytes[end]) {
+            end += 1;
+        }
+        if end > value_start {
+            redacted.push_str(&text[copied..value_start]);
+            redacted.push_str(REDACTED);
+            copied = end;
+        }
+
+        position = end;
+    }
+    redacted.push_str(&text[copied..]);
+
+    redact_tokens(&redacted)
+}
+
+fn is_value_end(byte: u8) -> bool {
+    matches!(
+        byte,
+        b'"' | b'\'' | b',' | b'}' | b'&' | b';' | b' ' | b'\t' | b'\r' | b'\n'
+    )
+}
+
+fn is_word_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_'
+}
+
+/// Where `word` next appears in `text` from `from` on, as a whole word, so
+/// `lichess_token` isn't found in `lichess_token_expires_at`
+fn find_word(text: &str, word: &str, from: usize) -> Option<usize> {
+    let bytes = text.as_bytes();
+    let mut position = from;
+
+    while let Some(at) = text[position..].find(word) {
+        let start = position + at;
+        let end = start + word.len();
+        let starts_word = start == 0 || !is_word_byte(bytes[start - 1]);
+        let ends_word = end == bytes.len() || !is_word_byte(bytes[end]);
+        if starts_word && ends_word {
+            return Some(start);
+        }
+        position = end;
+    }
+
+    None
+}
+
+fn redact_tokens(text: &str) -> String {
+    TOKEN_PREFIXES
+        .iter()
+        .fold(text.to_string(), |text, prefix| {
+            redact_prefixed(&text, prefix)
+        })
+}
+
+fn redact_prefixed(text: &str, prefix: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut redacted = String::with_capacity(text.len());
+    let mut copied = 0;
+    let mut position = 0;
+
+    while let Some(at) = text[position..].find(prefix) {
+        let start = position + at;
+        let end = start
+            + text[start..]
+                .bytes()
+                .take_while(|byte| is_word_byte(*byte))
+                .count();
+        position = end;
+
+        // only tokens that start a word, not `flip_board`
+        if start > 0 && is_word_byte(bytes[start - 1]) {
+            continue;
+        }
+        redacted.push_str(&text[copied..start]);
+        redacted.push_str(REDACTED);
+        copied = end;
+    }
+    redacted.push_str(&text[copied..]);
+
+    redacted
+}
+
+/// Redacts each write. The formatter writes a whole event at once, so a
+/// secret is never split between two writes.
+struct Redacting<M>(M);
+
+impl<'a, M: MakeWriter<'a>> MakeWriter<'a> for Redacting<M> {
+    type Writer = RedactingWriter<M::Writer>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(self.0.make_writer())
+    }
+}
+
+struct RedactingWriter<W>(W);
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .write_all(redact(&String::from_utf8_lossy(buf)).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Log files, oldest first
+fn log_files(dir: &Path) -> Vec<PathBuf> {
+    let mut files: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| entry.path())
+            // engine logs are rotated to `.log.1`
+            .filter(|path| path.to_string_lossy().contains(".log"))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    // the date in the name sorts them
+    files.sort();
+    files
+}
+
+/// The last lines of the app's logs
+pub fn recent(lines: usize) -> Result<Vec<String>, String> {
+    let mut recent: Vec<String> = Vec::new();
+
+    for path in log_files(&logs_dir()).iter().rev() {
+        let contents = fs::read_to_string(path)
+            .map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+        let mut file_lines: Vec<String> = contents.lines().map(String::from).collect();
+        file_lines.append(&mut recent);
+        recent = file_lines;
+
+        if recent.len() >= lines {
+            break;
+        }
+    }
+
+    let skip = recent.len().saturating_sub(lines);
+    Ok(recent.split_off(skip))
+}
+
+/// Writes a zip for bug reports: the app and engine logs, system info, the
+/// settings and how engines are set up. Secrets are left out.
+pub fn export_diagnostics(path: &Path, sysinfo: Value) -> Result<(), Box<dyn Error>> {
+    let mut zip = ZipWriter::new(File::create(path)?);
+    let options = FileOptions::default();
+
+    for (folder, dir) in [
+        ("logs", logs_dir()),
+        ("engine-logs", engine_log::logs_dir()),
+    ] {
+        for file in log_files(&dir) {
+            let name = file.file_name().unwrap_or_default().to_string_lossy();
+            // older files may predate redaction
+            let contents = redact(&String::from_utf8_lossy(&fs::read(&file)?));
+            zip.start_file(format!("{}/{}", folder, name), options)?;
+            zip.write_all(contents.as_bytes())?;
+        }
+    }
+
+    // secrets are never serialized
+    let settings = serde_json::to_string_pretty(&settings::get())?;
+    let engines: Vec<Value> = db::get_all_engine_binary_paths()
+        .into_iter()
+        .map(|engine| {
+            json!({
+                "engine_id": engine.engine_id,
+                "binary_location": engine.binary_location,
+                "options": engine.options(),
+            })
+        })
+        .collect();
+
+    for (name, contents) in [
+        ("sysinfo.json", serde_json::to_string_pretty(&sysinfo)?),
+        ("settings.json", redact(&settings)),
+        ("engines.json", serde_json::to_string_pretty(&engines)?),
+    ] {
+        zip.start_file(name, options)?;
+        zip.write_all(contents.as_bytes())?;
+    }
+
+    zip.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::redact;
+
+    #[test]
+    fn redacts_bearer_tokens() {
+        assert_eq!(
+            redact("Authorization: Bearer abc123"),
+            "Authorization: Bearer [redacted]"
+        );
+    }
+
+    #[test]
+    fn redacts_json_values() {
+        assert_eq!(
+            redact(r#"{"name":"Stockfish","providerSecret":"s3cr3t","maxThreads":8}"#),
+            r#"{"name":"Stockfish","providerSecret":"[redacted]","maxThreads":8}"#
+        );
+    }
+
+    #[test]
+    fn redacts_form_values() {
+        assert_eq!(
+            redact("grant_type=authorization_code&client_secret=s3cr3t&code=abc"),
+            "grant_type=authorization_code&client_secret=[redacted]&code=abc"
+        );
+    }
+
+    #[test]
+    fn redacts_bare_tokens() {
+        assert_eq!(
+            redact("sending lip_AbC123xyz to lichess.org"),
+            "sending [redacted] to lichess.org"
+        );
+        assert_eq!(
+            redact("token lio_AbC123xyz expires soon"),
+            "token [redacted] expires soon"
+        );
+        assert_eq!(redact("flip_board=true"), "flip_board=true");
+    }
+
+    #[test]
+    fn ignores_keys_inside_other_keys() {
+        assert_eq!(
+            redact(r#"{"lichess_token_expires_at": 1700000000, "lichess_token": "abc"}"#),
+            r#"{"lichess_token_expires_at": 1700000000, "lichess_token": "[redacted]"}"#
+        );
+    }
+}
@@ -1,13 +1,45 @@
-use std::borrow::Cow;
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    error::Error,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use reqwest::Url;
-use tauri::Window;
+use oauth2::{CsrfToken, PkceCodeChallenge, PkceCodeVerifier};
+use once_cell::sync::Lazy;
+use reqwest::{StatusCode, Url};
+use serde_json::json;
 use tauri_plugin_oauth::OauthConfig;
+use tracing::{info, warn};
 
-use crate::{db, utils::open_path};
+use crate::{
+    accounts,
+    events::EventSink,
+    http,
+    lichess::{send_status_to_frontend, StatusLevel, StatusPayload},
+    settings,
+    utils::open_path,
+};
 
 const OAUTH_CLIENT_ID: &str = "github.com/fitztrev/lichess-tauri";
 
+/// How long to wait for the user to approve the login in their browser
+const OAUTH_TIMEOUT: Duration = Duration::from_secs(300);
+
+/// How often the stored token is checked against Lichess
+const TOKEN_CHECK_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Set when Lichess rejected the stored token, until the user logs in again
+static RELOGIN_REQUIRED: AtomicBool = AtomicBool::new(false);
+
+/// Port of the local redirect server for the login in progress, if any
+static ACTIVE_FLOW: Lazy<Mutex<Option<u16>>> = Lazy::new(|| Mutex::new(None));
+
 #[allow(dead_code)]
 #[derive(serde::Deserialize, Debug)]
 struct AccessTokenResponse {
@@ -16,85 +48,424 @@ struct AccessTokenResponse {
     expires_in: i32,
 }
 
+#[derive(serde::Deserialize, Debug)]
+struct TokenErrorResponse {
+    error: String,
+    error_description: Option<String>,
+}
+
+/// One entry of the `/api/token/test` response, `null` for unknown tokens
+#[derive(serde::Deserialize, Debug)]
+struct TokenInfo {
+    scopes: String,
+    /// Milliseconds since the epoch, `null` for tokens that don't expire
+    expires: Option<i64>,
+}
+
 #[derive(serde::Deserialize, Debug)]
 struct LichessAccount {
     username: String,
 }
 
-pub fn start_oauth_flow(window: Window) {
-    let (code_challenge, code_verify) = oauth2::PkceCodeChallenge::new_random_sha256();
+pub fn start_oauth_flow(sink: Arc<dyn EventSink>) -> Result<(), String> {
+    let mut active_flow = ACTIVE_FLOW.lock().unwrap();
+    // the user gave up on the last login (closed the tab, say) and starts over
+    if let Some(port) = active_flow.take() {
+        stop_server(port);
+    }
+
+    let (code_challenge, code_verifier) = PkceCodeChallenge::new_random_sha256();
+    let state = CsrfToken::new_random();
+    let expected_state = state.secret().clone();
+    let lichess_host = settings::get().lichess_host;
 
+    let callback_sink = sink.clone();
+    let callback_lichess_host = lichess_host.clone();
     let port = tauri_plugin_oauth::start_with_config(
         OauthConfig {
             ports: None,
             response: Some(Cow::Borrowed(include_str!("../public/oauth_response.html"))),
         },
         move |url| {
-            let url = Url::parse(&url).unwrap();
-            let code = url.query_pairs().find(|(key, _)| key == "code").unwrap().1;
-
-            let lichess_host = db::get_setting("lichess_host").unwrap();
-
-            let body = reqwest::blocking::Client::new()
-                .post(format!("{}/api/token", lichess_host))
-                .form(&[
-                    ("grant_type", "authorization_code"),
-                    ("client_id", OAUTH_CLIENT_ID),
-                    ("code", code.to_string().as_str()),
-                    (
-                        "redirect_uri",
-                        format!("http://localhost:{}/", url.port().unwrap()).as_str(),
-                    ),
-                    ("code_verifier", code_verify.secret()),
-                ])
-                .send()
-                .unwrap()
-                .json::<AccessTokenResponse>()
-                .unwrap();
-
-            db::update_setting("lichess_token", &body.access_token);
+            let port = match Url::parse(&url).ok().and_then(|url| url.port()) {
+                Some(port) => port,
+                None => return,
+            };
 
-            let me = reqwest::blocking::Client::new()
-                .get(format!("{}/api/account", lichess_host))
-                .bearer_auth(&body.access_token)
-                .send()
-                .unwrap()
-                .json::<LichessAccount>()
-                .unwrap();
+            // the server keeps listening, so only the first redirect counts
+            if !finish_flow(port) {
+                return;
+            }
 
-            db::update_setting("lichess_username", &me.username);
-            window.emit("refresh_settings_from_database", ()).unwrap();
+            match handle_redirect(
+                &url,
+                &expected_state,
+                &code_verifier,
+                &callback_lichess_host,
+            ) {
+                Ok(_) => callback_sink.send("refresh_settings_from_database", ()),
+                Err(reason) => login_failed(callback_sink.as_ref(), &reason),
+            }
         },
     )
-    .unwrap();
+    .map_err(|e| format!("Error starting the local login server: {}", e))?;
+
+    *active_flow = Some(port);
+    drop(active_flow);
+
+    thread::spawn(move || {
+        thread::sleep(OAUTH_TIMEOUT);
+        if finish_flow(port) {
+            login_failed(
+                sink.as_ref(),
+                "Timed out waiting for the login to be approved on Lichess",
+            );
+        }
+    });
 
     let redirect_url = format!("http://localhost:{}/", port);
-    println!("Local server started: {}", redirect_url);
+    info!("Local server started: {}", redirect_url);
 
-    let lichess_host = db::get_setting("lichess_host").unwrap();
     let url = format!(
-        "{}/oauth?response_type=code&client_id={}&redirect_uri={}&code_challenge_method=S256&code_challenge={}&scope=engine:read%20engine:write",
+        "{}/oauth?response_type=code&client_id={}&redirect_uri={}&code_challenge_method=S256&code_challenge={}&scope=engine:read%20engine:write&state={}",
         lichess_host,
         OAUTH_CLIENT_ID,
         redirect_url,
-        code_challenge.as_str()
+        code_challenge.as_str(),
+        state.secret()
     );
 
     open_path(url);
+
+    Ok(())
 }
 
-pub fn logout(window: Window) {
-    let lichess_host = db::get_setting("lichess_host").unwrap();
-    let token = db::get_setting("lichess_token").unwrap();
+/// Ends the flow listening on `port`, returning false if it had already ended
+fn finish_flow(port: u16) -> bool {
+    let mut active_flow = ACTIVE_FLOW.lock().unwrap();
+    if *active_flow != Some(port) {
+        return false;
+    }
+    *active_flow = None;
+    stop_server(port);
 
-    reqwest::blocking::Client::new()
-        .delete(format!("{}/api/token", lichess_host))
-        .bearer_auth(token)
+    true
+}
+
+fn stop_server(port: u16) {
+    // cancelling connects to the server, so don't do it from its own handler thread
+    thread::spawn(move || {
+        if let Err(e) = tauri_plugin_oauth::cancel(port) {
+            warn!("Error stopping the local login server: {}", e);
+        }
+    });
+}
+
+fn login_failed(sink: &dyn EventSink, reason: &str) {
+    warn!("Login failed: {}", reason);
+    sink.send("login_failed", json!({ "reason": reason }));
+}
+
+fn handle_redirect(
+    url: &str,
+    expected_state: &str,
+    code_verifier: &PkceCodeVerifier,
+    lichess_host: &str,
+) -> Result<String, String> {
+    let url = Url::parse(url).map_err(|e| format!("Invalid redirect: {}", e))?;
+    let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+
+    if let Some(error) = params.get("error") {
+        return Err(match error.as_str() {
+            "access_denied" => "Access was denied on Lichess".to_string(),
+            _ => format!(
+                "Lichess returned an error: {}",
+                params.get("error_description").unwrap_or(error)
+            ),
+        });
+    }
+
+    if params.get("state").map(String::as_str) != Some(expected_state) {
+        return Err("The login response did not match this login request".to_string());
+    }
+
+    let code = params
+        .get("code")
+        .ok_or("Lichess did not return an authorization code")?;
+
+    let response = http::client()?
+        .post(format!("{}/api/token", lichess_host))
+        .form(&[
+            ("grant_type", "authorization_code"),
+            ("client_id", OAUTH_CLIENT_ID),
+            ("code", code.as_str()),
+            (
+                "redirect_uri",
+                format!("http://localhost:{}/", url.port().unwrap_or_default()).as_str(),
+            ),
+            ("code_verifier", code_verifier.secret()),
+        ])
+        .send()
+        .map_err(|e| format!("Error requesting an access token: {}", e))?;
+
+    if !response.status().is_success() {
+        let status = response.status();
+        return Err(match response.json::<TokenErrorResponse>() {
+            Ok(body) => format!(
+                "Lichess refused the access token request: {}",
+                body.error_description.unwrap_or(body.error)
+            ),
+            Err(_) => format!("Lichess refused the access token request ({})", status),
+        });
+    }
+
+    let body = response
+        .json::<AccessTokenResponse>()
+        .map_err(|e| format!("Invalid access token response: {}", e))?;
+
+    let expires_at = unix_now() + i64::from(body.expires_in);
+
+    store_token(lichess_host, &body.access_token, Some(expires_at))
+        .map_err(|e| format!("Error fetching the Lichess account: {}", e))
+}
+
+/// Logs in with a personal API token pasted by the user, for machines that
+/// can't open a browser for the OAuth flow
+pub fn login_with_token(token: &str) -> Result<String, String> {
+    let token = token.trim();
+    let lichess_host = settings::get().lichess_host;
+    let expires_at = verify_token(&lichess_host, token)?;
+
+    store_token(&lichess_host, token, expires_at)
+        .map_err(|e| format!("Error fetching the Lichess account: {}", e))
+}
+
+/// Checks a personal API token with Lichess and returns when it expires
+pub fn verify_token(lichess_host: &str, token: &str) -> Result<Option<i64>, String> {
+    if token.is_empty() {
+        return Err("Enter a Lichess API token".to_string());
+    }
+
+    let mut tokens = http::client()?
+        .post(format!("{}/api/token/test", lichess_host))
+        .body(token.to_string())
         .send()
-        .unwrap();
+        .and_then(|response| response.error_for_status())
+        .and_then(|response| response.json::<HashMap<String, Option<TokenInfo>>>())
+        .map_err(|e| format!("Error checking the token with Lichess: {}", e))?;
+
+    let info = tokens
+        .remove(token)
+        .flatten()
+        .ok_or("Lichess does not recognize this token")?;
+
+    let scopes: Vec<&str> = info.scopes.split(',').map(str::trim).collect();
+    let missing: Vec<&str> = ["engine:read", "engine:write"]
+        .into_iter()
+        .filter(|scope| !scopes.contains(scope))
+        .collect();
+    if !missing.is_empty() {
+        return Err(format!(
+            "The token is missing the {} scope",
+            missing.join(" and ")
+        ));
+    }
+
+    Ok(info.expires.map(|ms| ms / 1000))
+}
+
+/// Username of the account the token belongs to
+pub fn fetch_username(lichess_host: &str, token: &str) -> Result<String, Box<dyn Error>> {
+    let me = http::client()?
+        .get(format!("{}/api/account", lichess_host))
+        .bearer_auth(token)
+        .send()?
+        .error_for_status()?
+        .json::<LichessAccount>()?;
+
+    Ok(me.username)
+}
+
+/// Saves an access token along with the username of the account it belongs to
+pub fn store_token(
+    lichess_host: &str,
+    token: &str,
+    expires_at: Option<i64>,
+) -> Result<String, Box<dyn Error>> {
+    let username = fetch_username(lichess_host, token)?;
+
+    settings::update("lichess_token", token)?;
+    settings::update("lichess_username", &username)?;
+    match expires_at {
+        Some(expires_at) => settings::update("lichess_token_expires_at", &expires_at.to_string())?,
+        None => settings::delete("lichess_token_expires_at")?,
+    }
+
+    RELOGIN_REQUIRED.store(false, Ordering::SeqCst);
+    info!("Logged in to {} as {}", lichess_host, username);
+
+    Ok(username)
+}
+
+pub fn logout() {
+    let settings = settings::get();
+
+    if let Some(token) = settings.lichess_token {
+        // the token is forgotten locally even if Lichess can't be told
+        let revoked = http::client().and_then(|client| {
+            client
+                .delete(format!("{}/api/token", settings.lichess_host))
+                .bearer_auth(token)
+                .send()
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = revoked {
+            warn!("Error revoking the Lichess token: {}", e);
+        }
+    }
+
+    forget_token();
+}
+
+fn forget_token() {
+    for key in [
+        "lichess_token",
+        "lichess_username",
+        "lichess_token_expires_at",
+    ] {
+        if let Err(e) = settings::delete(key) {
+            warn!("Error deleting {}: {}", key, e);
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs() as i64)
+        .unwrap_or_default()
+}
+
+/// Whether the last token was rejected by Lichess and hasn't been replaced yet
+pub fn relogin_required() -> bool {
+    RELOGIN_REQUIRED.load(Ordering::SeqCst)
+}
+
+/// Forgets a token Lichess no longer accepts and asks the user to log in again
+pub fn expire_token(sink: &dyn EventSink, reason: &str) {
+    RELOGIN_REQUIRED.store(true, Ordering::SeqCst);
+    forget_token();
+
+    send_status_to_frontend(
+        sink,
+        StatusPayload {
+            status: reason.to_string(),
+            level: StatusLevel::Error,
+        },
+    );
+    sink.send("refresh_settings_from_database", ());
+}
+
+/// `Ok(false)` when Lichess says the token is no longer valid
+fn check_token(lichess_host: &str, token: &str) -> Result<bool, Box<dyn Error>> {
+    let response = http::client()?
+        .get(format!("{}/api/account", lichess_host))
+        .bearer_auth(token)
+        .send()?;
+
+    if response.status() == StatusCode::UNAUTHORIZED {
+        return Ok(false);
+    }
+
+    response.error_for_status()?;
+    Ok(true)
+}
+
+/// The login of an account last checked with Lichess, and when
+struct TokenCheck {
+    lichess_host: String,
+    token: String,
+    expires_at: Option<i64>,
+    checked_at: Instant,
+}
+
+/// Periodically checks that the stored tokens still work, so an expired or
+/// revoked token is noticed even while no analysis is requested. Settings
+/// changes wake it up, but only a new token, host or expiry is checked again
+/// before the interval is up.
+pub fn watch_token(sink: &dyn EventSink) {
+    let settings_changes = settings::subscribe();
+    let mut checks: HashMap<i32, TokenCheck> = HashMap::new();
+
+    loop {
+        let mut wait = TOKEN_CHECK_INTERVAL;
+        let mut next_checks = HashMap::new();
+
+        for account in accounts::all() {
+            let token = match account.lichess_token {
+                Some(token) => token,
+                None => continue,
+            };
+            let now = unix_now();
+
+            // expiring or forgetting the token of the default account changes
+            // the settings and wakes the loop up right away; if that failed,
+            // this waits instead of spinning
+            match account.token_expires_at {
+                Some(expires_at) if expires_at <= now => {
+                    accounts::expire_token(
+                        sink,
+                        account.id,
+                        "Lichess login expired, please log in again",
+                    );
+                    continue;
+                }
+                Some(expires_at) => {
+                    wait = wait.min(Duration::from_secs((expires_at - now) as u64));
+                }
+                None => {}
+            }
+
+            let check = match checks.remove(&account.id) {
+                Some(check)
+                    if check.lichess_host == account.lichess_host
+                        && check.token == token
+                        && check.expires_at == account.token_expires_at
+                        && check.checked_at.elapsed() < TOKEN_CHECK_INTERVAL =>
+                {
+                    check
+                }
+                _ => {
+                    match check_token(&account.lichess_host, &token) {
+                        Ok(true) => {}
+                        Ok(false) => {
+                            accounts::expire_token(
+                                sink,
+                                account.id,
+                                "Lichess rejected the login, please log in again",
+                            );
+                            continue;
+                        }
+                        Err(e) => warn!(
+                            "Error checking the Lichess token of account {}: {}",
+                            account.id, e
+                        ),
+                    }
+
+                    TokenCheck {
+                        lichess_host: account.lichess_host,
+                        token,
+                        expires_at: account.token_expires_at,
+                        checked_at: Instant::now(),
+                    }
+                }
+            };
 
-    db::delete_setting("lichess_token");
-    db::delete_setting("lichess_username");
+            wait = wait.min(TOKEN_CHECK_INTERVAL.saturating_sub(check.checked_at.elapsed()));
+            next_checks.insert(account.id, check);
+        }
 
-    window.emit("refresh_settings_from_database", ()).unwrap();
+        checks = next_checks;
+        let _ = settings_changes.recv_timeout(wait);
+    }
 }
@@ -3,19 +3,45 @@
     windows_subsystem = "windows"
 )]
 
+use accounts::{Account, DEFAULT_ACCOUNT};
+use db::EngineOptions;
 use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
 use engine_directory::Engine;
+use engine_info::EngineTest;
+use engine_sync::Repair;
+use events::{EventSink, TauriSink};
+use lichess_api::{ApiError, ExternalEngine, LichessApi, NewEngine};
 use login::start_oauth_flow;
+use sandbox::Placement;
 use serde_json::{json, Value};
-use std::thread;
+use std::{path::Path, sync::Arc, thread};
 use sysinfo::{CpuExt, System, SystemExt};
-use tauri::Window;
+use tauri::AppHandle;
+use tracing::info;
 
 use crate::db::establish_connection;
 
+mod accounts;
+mod backoff;
+mod connectivity;
 mod engine_directory;
+mod engine_info;
+mod engine_log;
+mod engine_sync;
+mod events;
+mod headless;
+mod http;
 mod lichess;
+mod lichess_api;
+mod logging;
 mod login;
+mod pgn;
+mod resource_policy;
+mod sandbox;
+mod secrets;
+mod settings;
+mod uci;
+mod watchdog;
 
 pub mod db;
 pub mod schema;
@@ -23,29 +49,171 @@ pub mod utils;
 
 #[tauri::command]
 fn get_all_settings() -> Value {
-    let settings = db::get_all_settings();
+    json!(settings::get())
+}
+
+#[tauri::command]
+fn update_setting(key: &str, value: &str) -> Result<(), String> {
+    if settings::ACCOUNT_KEYS.contains(&key) {
+        return Err(format!("{} is managed by the accounts", key));
+    }
+
+    settings::update(key, value).map_err(|e| e.to_string())
+}
+
+/// Starts the binary, runs the UCI handshake and a short search, and reports
+/// what the engine said about itself. The binary runs without the sandbox.
+#[tauri::command(async)]
+fn test_engine(path: &str) -> Result<EngineTest, String> {
+    engine_info::test(path).map_err(|e| e.to_string())
+}
+
+/// Links an engine to a binary, if the binary works
+#[tauri::command(async)]
+fn add_engine(engine_id: &str, binary_location: &str) -> Result<(), String> {
+    engine_info::test(binary_location).map_err(|e| e.to_string())?;
+    db::add_engine(engine_id, binary_location);
+    Ok(())
+}
+
+/// Runs a call against the external engine API of an account (the default
+/// one unless given), logging the account out if Lichess rejects its token
+fn with_engine_api<T>(
+    app_handle: AppHandle,
+    account_id: Option<i32>,
+    call: impl FnOnce(&LichessApi, &Account) -> Result<T, ApiError>,
+) -> Result<T, ApiError> {
+    let account =
+        accounts::get(account_id.unwrap_or(DEFAULT_ACCOUNT)).ok_or(ApiError::NotLoggedIn)?;
 
-    let mut json = json!({});
-    for setting in settings {
-        json[setting.key] = json!(setting.value);
+    let result = call(&LichessApi::for_account(&account)?, &account);
+    if let Err(ApiError::Unauthorized) = result {
+        accounts::expire_token(
+            &TauriSink(app_handle),
+            account.id,
+            "Lichess rejected the login, please log in again",
+        );
     }
+    result
+}
+
+#[tauri::command(async)]
+fn list_engines(
+    app_handle: AppHandle,
+    account_id: Option<i32>,
+) -> Result<Vec<ExternalEngine>, ApiError> {
+    with_engine_api(app_handle, account_id, |api, _| api.list_engines())
+}
 
-    json
+/// Registers the engine on Lichess and links it to the binary
+#[tauri::command(async)]
+fn register_engine(
+    app_handle: AppHandle,
+    account_id: Option<i32>,
+    engine: NewEngine,
+    binary_location: &str,
+) -> Result<ExternalEngine, ApiError> {
+    engine_info::check_binary(binary_location)?;
+
+    with_engine_api(app_handle, account_id, |api, account| {
+        let engine = api.register_engine(&engine.registration(&account.provider_secret))?;
+        db::add_engine(&engine.id, binary_location);
+        Ok(engine)
+    })
+}
+
+#[tauri::command(async)]
+fn update_engine(
+    app_handle: AppHandle,
+    account_id: Option<i32>,
+    engine_id: &str,
+    engine: NewEngine,
+) -> Result<ExternalEngine, ApiError> {
+    with_engine_api(app_handle, account_id, |api, account| {
+        api.update_engine(engine_id, &engine.registration(&account.provider_secret))
+    })
 }
 
+/// Deletes the engine on Lichess and forgets its binary
+#[tauri::command(async)]
+fn delete_engine(
+    app_handle: AppHandle,
+    account_id: Option<i32>,
+    engine_id: &str,
+) -> Result<(), ApiError> {
+    with_engine_api(app_handle, account_id, |api, _| {
+        match api.delete_engine(engine_id) {
+            // already gone on Lichess, only the local link is left
+            Ok(_) | Err(ApiError::NotFound) => {
+                db::delete_engine(engine_id);
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    })
+}
+
+/// The last lines of an engine's stderr, with when it started and how it exited
 #[tauri::command]
-fn update_setting(key: &str, value: &str) {
-    db::update_setting(key, value);
+fn get_engine_logs(engine_id: &str, lines: Option<usize>) -> Result<Vec<String>, String> {
+    engine_log::recent(engine_id, lines.unwrap_or(200))
 }
 
+/// The last lines of the app's own logs
 #[tauri::command]
-fn add_engine(engine_id: &str, binary_location: &str) {
-    db::add_engine(engine_id, binary_location);
+fn get_logs(lines: Option<usize>) -> Result<Vec<String>, String> {
+    logging::recent(lines.unwrap_or(500))
+}
+
+/// Writes a zip of logs, system info and settings (without secrets) to
+/// attach to bug reports
+#[tauri::command]
+fn export_diagnostics(path: &str) -> Result<(), String> {
+    logging::export_diagnostics(Path::new(path), get_sysinfo()).map_err(|e| e.to_string())
 }
 
 #[tauri::command]
-fn delete_engine(engine_id: &str) {
-    db::delete_engine(engine_id);
+fn get_engine_options(engine_id: &str) -> Option<EngineOptions> {
+    db::get_engine(engine_id).map(|engine| engine.options())
+}
+
+/// Priority, limits and sandbox for the engine's process, applied the next
+/// time it starts
+#[tauri::command]
+fn update_engine_options(engine_id: &str, options: EngineOptions) -> Result<(), String> {
+    sandbox::validate(&options)?;
+    db::get_engine(engine_id).ok_or("Unknown engine")?;
+    db::update_engine_options(engine_id, &options);
+    Ok(())
+}
+
+/// Threads, hash and variants to register a binary with, derived from the
+/// machine and the options the engine advertises
+#[tauri::command(async)]
+fn suggest_engine(binary_location: &str, name: Option<String>) -> Result<NewEngine, String> {
+    let info = engine_info::probe(binary_location).map_err(|e| e.to_string())?;
+    let name = name
+        .or_else(|| info.name.clone())
+        .unwrap_or_else(|| "Local engine".to_string());
+
+    Ok(engine_info::suggested_engine(&name, &info))
+}
+
+#[tauri::command(async)]
+fn rotate_provider_secret(app_handle: AppHandle, account_id: Option<i32>) -> Result<(), ApiError> {
+    with_engine_api(app_handle, account_id, |api, account| {
+        accounts::rotate_provider_secret(account, api)
+    })
+}
+
+#[tauri::command(async)]
+fn sync_engines() -> Value {
+    json!(engine_sync::check())
+}
+
+#[tauri::command(async)]
+fn repair_engine(repair: Repair) -> Result<(), ApiError> {
+    engine_sync::repair(repair)
 }
 
 #[tauri::command]
@@ -75,12 +243,34 @@ fn get_sysinfo() -> Value {
         "cpu_frequency"  : sys.global_cpu_info().frequency(),
         "cpu_vendor_id"  : sys.global_cpu_info().vendor_id(),
         "cpu_name"       : sys.global_cpu_info().name(),
+        // what engines without their own priority and cores run with
+        "engine_placement": Placement::new(&EngineOptions::default(), &settings::get()),
     })
 }
 
+/// How each linked engine is started: its options and the priority and
+/// cores that result from them and the settings
+#[tauri::command]
+fn get_engine_status() -> Value {
+    let settings = settings::get();
+
+    db::get_all_engine_binary_paths()
+        .into_iter()
+        .map(|engine| {
+            let options = engine.options();
+            json!({
+                "engine_id": engine.engine_id,
+                "binary_location": engine.binary_location,
+                "placement": Placement::new(&options, &settings),
+                "options": options,
+            })
+        })
+        .collect()
+}
+
 #[tauri::command]
-fn download_engine_to_folder(engine: Engine) -> String {
-    engine_directory::install(engine)
+fn download_engine_to_folder(app_handle: AppHandle, engine: Engine) -> String {
+    engine_directory::install(engine, &TauriSink(app_handle))
         .into_os_string()
         .into_string()
         .unwrap()
@@ -95,40 +285,141 @@ fn get_app_data_dir() -> String {
 }
 
 #[tauri::command]
-fn login_with_lichess(window: Window) {
-    start_oauth_flow(window);
+fn get_analysis_sessions() -> Value {
+    let sessions = db::get_analysis_sessions();
+
+    json!(sessions
+        .into_iter()
+        .map(|(session_id, last_analyzed_at, analysis_count)| {
+            json!({
+                "session_id"      : session_id,
+                "last_analyzed_at": last_analyzed_at.to_string(),
+                "analysis_count"  : analysis_count,
+            })
+        })
+        .collect::<Vec<Value>>())
+}
+
+#[tauri::command]
+fn export_analysis_pgn(session_id: &str, path: &str) -> Result<(), String> {
+    let lichess_host = settings::get().lichess_host;
+    let analyses = db::get_session_analyses(session_id);
+
+    let pgn = pgn::session_to_pgn(&lichess_host, &analyses).map_err(|e| e.to_string())?;
+    std::fs::write(path, pgn).map_err(|e| e.to_string())
+}
+
+#[tauri::command]
+fn login_with_lichess(app_handle: AppHandle) -> Result<(), String> {
+    start_oauth_flow(Arc::new(TauriSink(app_handle)))
+}
+
+#[tauri::command]
+fn login_with_token(app_handle: AppHandle, token: &str) -> Result<(), String> {
+    login::login_with_token(token)?;
+    TauriSink(app_handle).emit("refresh_settings_from_database", Value::Null);
+    Ok(())
+}
+
+#[tauri::command]
+fn get_accounts() -> Value {
+    json!(accounts::all())
+}
+
+#[tauri::command]
+fn add_account(
+    app_handle: AppHandle,
+    lichess_host: &str,
+    engine_host: &str,
+    token: &str,
+) -> Result<Value, String> {
+    let account = accounts::add(lichess_host, engine_host, token)?;
+    lichess::start_provider(Arc::new(TauriSink(app_handle)), account.id);
+    Ok(json!(account))
 }
 
 #[tauri::command]
-fn logout(window: Window) {
-    login::logout(window);
+fn remove_account(id: i32) -> Result<(), String> {
+    accounts::remove(id)
+}
+
+#[tauri::command]
+fn set_account_enabled(app_handle: AppHandle, id: i32, enabled: bool) -> Result<(), String> {
+    accounts::set_enabled(id, enabled)?;
+    if enabled {
+        lichess::start_provider(Arc::new(TauriSink(app_handle)), id);
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn logout(app_handle: AppHandle) {
+    login::logout();
+    TauriSink(app_handle).emit("refresh_settings_from_database", Value::Null);
 }
 
 fn main() {
+    logging::init();
+
     pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!();
-    let mut connection = establish_connection();
-    connection.run_pending_migrations(MIGRATIONS).unwrap();
+    {
+        // returned to the pool before the app starts, which needs it back
+        let mut connection = establish_connection();
+        for migration in connection.run_pending_migrations(MIGRATIONS).unwrap() {
+            info!("Applied database migration {}", migration);
+        }
+    }
+
+    logging::follow_settings();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("--headless") {
+        std::process::exit(headless::run(&args[1..]));
+    }
 
     tauri::Builder::default()
         .invoke_handler(tauri::generate_handler![
+            add_account,
             add_engine,
             delete_engine,
             download_engine_to_folder,
+            export_analysis_pgn,
+            export_diagnostics,
+            get_accounts,
             get_all_settings,
+            get_analysis_sessions,
             get_app_data_dir,
+            get_engine_logs,
+            get_engine_options,
+            get_engine_status,
+            get_logs,
             get_sysinfo,
+            list_engines,
             login_with_lichess,
+            login_with_token,
             logout,
+            remove_account,
+            set_account_enabled,
             update_setting,
-            open_path
+            open_path,
+            register_engine,
+            repair_engine,
+            rotate_provider_secret,
+            suggest_engine,
+            sync_engines,
+            test_engine,
+            update_engine,
+            update_engine_options
         ])
         .setup(|app| {
-            let app_handle = app.handle();
+            let token_sink = TauriSink(app.handle());
+
+            thread::spawn(move || login::watch_token(&token_sink));
+
+            let sync_sink = TauriSink(app.handle());
+            thread::spawn(move || engine_sync::check_at_startup(&sync_sink));
 
-            thread::spawn(move || match lichess::work(&app_handle) {
-                Ok(_) => println!("Success"),
-                Err(e) => println!("Error: {}", e),
-            });
+            lichess::start_providers(Arc::new(TauriSink(app.handle())));
             Ok(())
         })
         .run(tauri::generate_context!())
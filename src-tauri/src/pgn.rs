@@ -0,0 +1,306 @@
+use std::{collections::HashMap, error::Error, fmt::Write};
+
+use shakmaty::{
+    fen::Fen,
+    san::SanPlus,
+    uci::UciMove,
+    variant::{Variant, VariantPosition},
+    CastlingMode, Color, Position,
+};
+
+use crate::{
+    db::SqlAnalysis,
+    uci::{Info, Score},
+};
+
+const STARTING_FEN: &str = "rnbqkbnr/pppppppp/8/8/8/8/PPPPPPPP/RNBQKBNR w KQkq - 0 1";
+
+/// Builds an annotated PGN out of every analysis stored for one session.
+///
+/// The longest move sequence of the session becomes the mainline. Every other
+/// analysis whose moves are a prefix of it contributes an `[%eval]` comment
+/// after the move that reached the analyzed position, and its principal
+/// variations as alternatives to the mainline move played from there.
+pub fn session_to_pgn(
+    lichess_host: &str,
+    analyses: &[SqlAnalysis],
+) -> Result<String, Box<dyn Error>> {
+    let mainline_analysis = analyses
+        .iter()
+        .max_by_key(|analysis| split_moves(&analysis.moves).len())
+        .ok_or("No analysis found for this session")?;
+
+    let variant = Variant::from_uci(&mainline_analysis.variant)?;
+    let fen: Fen = mainline_analysis.initial_fen.parse()?;
+    let initial_position =
+        VariantPosition::from_setup(variant, fen.clone().into_setup(), CastlingMode::Chess960)?;
+    let mainline = split_moves(&mainline_analysis.moves);
+
+    // analyses are ordered oldest first, so a re-analysis of a position wins
+    let mut lines_by_ply: HashMap<usize, Vec<Info>> = HashMap::new();
+    for analysis in analyses {
+        let moves = split_moves(&analysis.moves);
+        if analysis.initial_fen != mainline_analysis.initial_fen
+            || analysis.variant != mainline_analysis.variant
+            || !mainline.starts_with(&moves)
+            // kept only to record why it failed, and mustn't replace a good one
+            || analysis.submission_error.is_some()
+        {
+            continue;
+        }
+
+        let mut lines: Vec<Info> = serde_json::from_str(&analysis.lines)?;
+        if lines.is_empty() {
+            continue;
+        }
+        lines.sort_by_key(|line| line.multi_pv.unwrap_or(1));
+        lines_by_ply.insert(moves.len(), lines);
+    }
+
+    let mut pgn = String::new();
+
+    writeln!(pgn, "[Event \"Engine analysis\"]")?;
+    writeln!(pgn, "[Site \"{}\"]", lichess_host)?;
+    writeln!(
+        pgn,
+        "[Date \"{}\"]",
+        mainline_analysis.created_at.format("%Y.%m.%d")
+    )?;
+    writeln!(pgn, "[Round \"-\"]")?;
+    writeln!(pgn, "[White \"?\"]")?;
+    writeln!(pgn, "[Black \"?\"]")?;
+    writeln!(pgn, "[Result \"*\"]")?;
+    writeln!(pgn, "[Annotator \"{}\"]", mainline_analysis.engine_name)?;
+    if variant != Variant::Chess {
+        writeln!(pgn, "[Variant \"{}\"]", pgn_variant_name(variant))?;
+    }
+    if mainline_analysis.initial_fen != STARTING_FEN {
+        writeln!(pgn, "[SetUp \"1\"]")?;
+        writeln!(pgn, "[FEN \"{}\"]", fen)?;
+    }
+    writeln!(pgn)?;
+
+    let mut movetext: Vec<String> = Vec::new();
+
+    if let Some(lines) = lines_by_ply.get(&0) {
+        if let Some(comment) = eval_comment(&initial_position, lines) {
+            movetext.push(comment);
+        }
+        if mainline.is_empty() {
+            movetext.push(best_line_comment(&initial_position, lines));
+        }
+    }
+
+    let mut position = initial_position;
+    // a black move needs its number repeated after a comment or variation
+    let mut interrupted = true;
+    for (ply, uci) in mainline.iter().enumerate() {
+        let before = position.clone();
+        let m = uci.parse::<UciMove>()?.to_move(&position)?;
+        let san = SanPlus::from_move_and_play_unchecked(&mut position, m);
+
+        movetext.push(move_number(&before, interrupted).unwrap_or_default() + &san.to_string());
+        let move_index = movetext.len();
+
+        let lines_after = lines_by_ply.get(&(ply + 1));
+        if let Some(comment) = lines_after.and_then(|lines| eval_comment(&position, lines)) {
+            movetext.push(comment);
+        }
+
+        if let Some(lines_before) = lines_by_ply.get(&ply) {
+            for line in lines_before {
+                if line.pv.first() == Some(uci) {
+                    continue;
+                }
+                movetext.push(format!("({})", variation(&before, line, true)));
+            }
+        }
+
+        // no mainline move left to branch from, so describe the best line instead
+        if ply + 1 == mainline.len() {
+            if let Some(lines) = lines_after {
+                movetext.push(best_line_comment(&position, lines));
+            }
+        }
+
+        interrupted = movetext.len() > move_index;
+    }
+
+    movetext.push("*".to_string());
+
+    writeln!(pgn, "{}", movetext.join(" "))?;
+
+    Ok(pgn)
+}
+
+fn split_moves(moves: &str) -> Vec<String> {
+    moves.split_whitespace().map(String::from).collect()
+}
+
+fn move_number(position: &VariantPosition, force: bool) -> Option<String> {
+    match position.turn() {
+        Color::White => Some(format!("{}. ", position.fullmoves())),
+        Color::Black if force => Some(format!("{}... ", position.fullmoves())),
+        Color::Black => None,
+    }
+}
+
+/// Formats a UCI score (relative to the side to move) from White's point of view
+fn format_eval(position: &VariantPosition, score: &Score) -> String {
+    let sign = match position.turn() {
+        Color::White => 1,
+        Color::Black => -1,
+    };
+
+    match score {
+        Score::Cp(cp) => format!("{:.2}", f64::from(cp * sign) / 100.0),
+        Score::Mate(mate) => format!("#{}", mate * sign),
+    }
+}
+
+fn eval_comment(position: &VariantPosition, lines: &[Info]) -> Option<String> {
+    let best = lines.first()?;
+    let score = best.score.as_ref()?;
+
+    Some(format!(
+        "{{ [%eval {}{}] }}",
+        format_eval(position, score),
+        best.depth
+            .map(|depth| format!(",{}", depth))
+            .unwrap_or_default()
+    ))
+}
+
+fn best_line_comment(position: &VariantPosition, lines: &[Info]) -> String {
+    let best = lines
+        .first()
+        .map(|line| variation(position, line, false))
+        .unwrap_or_default();
+
+    format!("{{ Best line: {} }}", best)
+}
+
+/// Renders a principal variation in SAN, stopping at the first move that
+/// isn't legal (e.g. a truncated or stale PV)
+fn variation(root: &VariantPosition, line: &Info, with_eval: bool) -> String {
+    let mut position = root.clone();
+    let mut tokens: Vec<String> = Vec::new();
+    let mut interrupted = true;
+
+    for (index, uci) in line.pv.iter().enumerate() {
+        let m = match uci
+            .parse::<UciMove>()
+            .ok()
+            .and_then(|uci| uci.to_move(&position).ok())
+        {
+            Some(m) => m,
+            None => break,
+        };
+
+        let number = move_number(&position, interrupted).unwrap_or_default();
+        let san = SanPlus::from_move_and_play_unchecked(&mut position, m);
+        tokens.push(number + &san.to_string());
+        interrupted = false;
+
+        if index == 0 && with_eval {
+            if let Some(score) = &line.score {
+                tokens.push(format!("{{ [%eval {}] }}", format_eval(root, score)));
+                interrupted = true;
+            }
+        }
+    }
+
+    tokens.join(" ")
+}
+
+fn pgn_variant_name(variant: Variant) -> &'static str {
+    match variant {
+        Variant::Chess => "Standard",
+        Variant::Atomic => "Atomic",
+        Variant::Antichess => "Antichess",
+        Variant::KingOfTheHill => "King of the Hill",
+        Variant::ThreeCheck => "Three-check",
+        Variant::Crazyhouse => "Crazyhouse",
+        Variant::RacingKings => "Racing Kings",
+        Variant::Horde => "Horde",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::NaiveDate;
+
+    use super::*;
+
+    fn line(multi_pv: u32, depth: u32, cp: i32, pv: &str) -> Info {
+        Info {
+            depth: Some(depth),
+            multi_pv: Some(multi_pv),
+            score: Some(Score::Cp(cp)),
+            pv: split_moves(pv),
+            ..Info::default()
+        }
+    }
+
+    fn analysis(initial_fen: &str, moves: &str, lines: &[Info]) -> SqlAnalysis {
+        SqlAnalysis {
+            id: format!("analysis {}", moves),
+            session_id: "session".to_string(),
+            engine_id: "engine".to_string(),
+            engine_name: "Stockfish 16".to_string(),
+            variant: "chess".to_string(),
+            initial_fen: initial_fen.to_string(),
+            moves: moves.to_string(),
+            lines: serde_json::to_string(lines).unwrap(),
+            created_at: NaiveDate::from_ymd_opt(2024, 1, 2)
+                .unwrap()
+                .and_hms_opt(12, 0, 0)
+                .unwrap(),
+            submission_error: None,
+        }
+    }
+
+    #[test]
+    fn annotates_the_mainline_with_evals_and_variations() {
+        let analyses = [
+            analysis(
+                STARTING_FEN,
+                "",
+                &[line(2, 20, 25, "d2d4 d7d5"), line(1, 20, 30, "e2e4 e7e5")],
+            ),
+            analysis(STARTING_FEN, "e2e4 e7e5", &[line(1, 18, 35, "g1f3 b8c6")]),
+        ];
+
+        assert_eq!(
+            session_to_pgn("https://lichess.org", &analyses).unwrap(),
+            "[Event \"Engine analysis\"]\n\
+             [Site \"https://lichess.org\"]\n\
+             [Date \"2024.01.02\"]\n\
+             [Round \"-\"]\n\
+             [White \"?\"]\n\
+             [Black \"?\"]\n\
+             [Result \"*\"]\n\
+             [Annotator \"Stockfish 16\"]\n\
+             \n\
+             { [%eval 0.30,20] } 1. e4 (1. d4 { [%eval 0.25] } 1... d5) 1... e5 \
+             { [%eval 0.35,18] } { Best line: 2. Nf3 Nc6 } *\n"
+        );
+    }
+
+    #[test]
+    fn starts_from_a_custom_position_with_black_to_move() {
+        let fen = "4k3/8/8/8/8/8/4P3/4K3 b - - 0 40";
+        let analyses = [
+            analysis(fen, "", &[line(1, 12, -120, "e8e7")]),
+            analysis(fen, "e8d7 e2e4", &[line(1, 14, -150, "d7e6 e1e2")]),
+        ];
+
+        let pgn = session_to_pgn("https://lichess.org", &analyses).unwrap();
+
+        assert!(pgn.contains("[SetUp \"1\"]\n[FEN \"4k3/8/8/8/8/8/4P3/4K3 b - - 0 40\"]\n"));
+        assert!(pgn.ends_with(
+            "{ [%eval 1.20,12] } 40... Kd7 (40... Ke7 { [%eval 1.20] }) 41. e4 \
+             { [%eval 1.50,14] } { Best line: 41... Ke6 42. Ke2 } *\n"
+        ));
+    }
+}
@@ -0,0 +1,165 @@
+//! Decides whether the worker should take analysis requests right now, based
+//! on how busy the machine is, free memory, quiet hours and battery power.
+//!
+//! Every check is optional and off by default (see the settings). They run
+//! before each long poll, while the account's engine isn't running. Another
+//! account's engine may be, so CPU usage is only sampled while no engine at
+//! all runs, and the usage seen is that of other programs.
+
+use std::{
+    sync::atomic::{AtomicUsize, Ordering},
+    thread,
+    time::Duration,
+};
+
+use chrono::{Local, NaiveTime};
+use sysinfo::{CpuExt, System, SystemExt};
+
+use crate::settings::Settings;
+
+/// How long CPU usage is sampled for before each long poll
+const CPU_SAMPLE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Engines running for any account
+static ANALYSING: AtomicUsize = AtomicUsize::new(0);
+
+/// Counts an engine as running until dropped
+pub struct Analysing;
+
+impl Analysing {
+    pub fn start() -> Analysing {
+        ANALYSING.fetch_add(1, Ordering::SeqCst);
+        Analysing
+    }
+}
+
+impl Drop for Analysing {
+    fn drop(&mut self) {
+        ANALYSING.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Decision {
+    /// Take work, using at most this many threads if set
+    Run { max_threads: Option<u32> },
+    /// Don't poll for work, for the given reason
+    Pause(String),
+}
+
+/// Parses `HH:MM-HH:MM`. The range may wrap around midnight.
+pub fn parse_quiet_hours(value: &str) -> Option<(NaiveTime, NaiveTime)> {
+    let (start, end) = value.trim().split_once('-')?;
+    let start = NaiveTime::parse_from_str(start.trim(), "%H:%M").ok()?;
+    let end = NaiveTime::parse_from_str(end.trim(), "%H:%M").ok()?;
+    Some((start, end))
+}
+
+fn in_quiet_hours((start, end): (NaiveTime, NaiveTime), now: NaiveTime) -> bool {
+    if start <= end {
+        start <= now && now < end
+    } else {
+        now >= start || now < end
+    }
+}
+
+/// Whether the machine runs from a discharging battery. Only known on Linux,
+/// elsewhere this is always false.
+#[cfg(target_os = "linux")]
+fn on_battery() -> bool {
+    use std::fs;
+
+    let supplies = match fs::read_dir("/sys/class/power_supply") {
+        Ok(supplies) => supplies,
+        Err(_) => return false,
+    };
+
+    let read = |path: &std::path::Path, file: &str| {
+        fs::read_to_string(path.join(file))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    let mut discharging = false;
+    for supply in supplies.flatten() {
+        let path = supply.path();
+        match read(&path, "type").as_str() {
+            "Mains" | "USB" if read(&path, "online") == "1" => return false,
+            "Battery" if read(&path, "status") == "Discharging" => discharging = true,
+            _ => {}
+        }
+    }
+
+    discharging
+}
+
+#[cfg(not(target_os = "linux"))]
+fn on_battery() -> bool {
+    false
+}
+
+pub fn check(settings: &Settings) -> Decision {
+    if settings.pause_on_battery && on_battery() {
+        return Decision::Pause("Paused while on battery power".to_string());
+    }
+
+    if let Some(quiet_hours) = settings.quiet_hours.as_deref().and_then(parse_quiet_hours) {
+        if in_quiet_hours(quiet_hours, Local::now().time()) {
+            return Decision::Pause(format!(
+                "Paused during quiet hours ({})",
+                settings.quiet_hours.as_deref().unwrap_or_default()
+            ));
+        }
+    }
+
+    if settings.min_free_memory_mb.is_none() && settings.busy_cpu_percent.is_none() {
+        return Decision::Run { max_threads: None };
+    }
+
+    let mut sys = System::new();
+
+    if let Some(min_free_memory_mb) = settings.min_free_memory_mb {
+        sys.refresh_memory();
+        let available_mb = sys.available_memory() / 1024 / 1024;
+        if available_mb < min_free_memory_mb {
+            return Decision::Pause(format!(
+                "Paused while memory is low ({} MB free)",
+                available_mb
+            ));
+        }
+    }
+
+    let busy_cpu_percent = match settings.busy_cpu_percent {
+        Some(busy_cpu_percent) => busy_cpu_percent as f32,
+        None => return Decision::Run { max_threads: None },
+    };
+
+    // our own engines would make the machine look busy
+    if ANALYSING.load(Ordering::SeqCst) > 0 {
+        return Decision::Run { max_threads: None };
+    }
+
+    // usage is measured between two refreshes
+    sys.refresh_cpu();
+    thread::sleep(CPU_SAMPLE_INTERVAL);
+    sys.refresh_cpu();
+
+    let usage = sys.global_cpu_info().cpu_usage();
+    if usage <= busy_cpu_percent {
+        return Decision::Run { max_threads: None };
+    }
+
+    let cores = sys.cpus().len();
+    let busy_cores = sys
+        .cpus()
+        .iter()
+        .filter(|cpu| cpu.cpu_usage() > busy_cpu_percent)
+        .count();
+
+    match cores - busy_cores {
+        0 => Decision::Pause(format!("Paused while the CPU is busy ({:.0}%)", usage)),
+        idle_cores => Decision::Run {
+            max_threads: Some(idle_cores as u32),
+        },
+    }
+}
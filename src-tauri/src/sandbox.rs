@@ -0,0 +1,601 @@
+//! Limits for engine processes.
+//!
+//! Engines are often downloaded binaries that would otherwise run with all of
+//! the user's rights. Per engine (see [`EngineOptions`]) their priority can be
+//! lowered, they can be kept to some cores (Linux only) and their memory and
+//! CPU time can be capped. Priority and cores default to the `engine_nice`,
+//! `engine_cpus` and `engine_free_cores` settings. On Linux they can also be
+//! sandboxed: Landlock restricts the filesystem to reading the engine's own
+//! directory, system libraries and any extra paths such as tablebases, and a
+//! seccomp filter refuses to create sockets, so the engine has no network.
+//!
+//! The sandbox fails closed: if the kernel doesn't support it, the engine
+//! isn't started.
+
+use std::{error::Error, process::Command};
+
+use serde::Serialize;
+
+use crate::{db::EngineOptions, settings::Settings};
+
+/// Whatever has to stay open until the process is spawned
+#[derive(Default)]
+pub struct Sandbox {
+    #[cfg(target_os = "linux")]
+    ruleset_fd: Option<std::os::unix::io::RawFd>,
+}
+
+#[cfg(target_os = "linux")]
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        if let Some(fd) = self.ruleset_fd {
+            unsafe { libc::close(fd) };
+        }
+    }
+}
+
+/// The priority and cores an engine runs with, once the settings are applied
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct Placement {
+    pub nice: Option<i32>,
+    /// Cores the engine is pinned to, all of them if unset
+    pub cpus: Option<Vec<usize>>,
+}
+
+impl Placement {
+    pub fn new(options: &EngineOptions, settings: &Settings) -> Self {
+        let nice = options.nice.or(settings.engine_nice);
+        let cpus = options
+            .cpus
+            .as_deref()
+            .or(settings.engine_cpus.as_deref())
+            .and_then(parse_cpus);
+        let free_cores = match options.free_cores {
+            Some(free_cores) => free_cores.max(0) as usize,
+            None => settings.engine_free_cores.unwrap_or(0) as usize,
+        };
+
+        #[cfg(target_os = "linux")]
+        let cpus = linux::pinned_cpus(cpus, free_cores);
+        // other platforms can't pin processes to cores
+        #[cfg(not(target_os = "linux"))]
+        let cpus = {
+            let _ = (cpus, free_cores);
+            None
+        };
+
+        Placement { nice, cpus }
+    }
+}
+
+/// Parses core lists like `0-3,6`
+pub fn parse_cpus(value: &str) -> Option<Vec<usize>> {
+    let mut cpus = Vec::new();
+
+    for part in value.split(',').map(str::trim) {
+        match part.split_once('-') {
+            Some((first, last)) => {
+                let first: usize = first.trim().parse().ok()?;
+                let last: usize = last.trim().parse().ok()?;
+                if first > last {
+                    return None;
+                }
+                cpus.extend(first..=last);
+            }
+            None => cpus.push(part.parse().ok()?),
+        }
+    }
+
+    cpus.sort_unstable();
+    cpus.dedup();
+    Some(cpus)
+}
+
+pub fn allowed_paths(options: &EngineOptions) -> Vec<&str> {
+    options
+        .allowed_paths
+        .lines()
+        .map(str::trim)
+        .filter(|path| !path.is_empty())
+        .collect()
+}
+
+/// Catches options that would only fail once the engine is started
+pub fn validate(options: &EngineOptions) -> Result<(), String> {
+    if let Some(nice) = options.nice {
+        // raising the priority needs privileges
+        if !(0..=19).contains(&nice) {
+            return Err("nice must be between 0 and 19".to_string());
+        }
+    }
+    if options
+        .memory_limit_mb
+        .map_or(false, |megabytes| megabytes < 1)
+    {
+        return Err("memory_limit_mb must be positive".to_string());
+    }
+    if options
+        .cpu_time_limit_secs
+        .map_or(false, |seconds| seconds < 1)
+    {
+        return Err("cpu_time_limit_secs must be positive".to_string());
+    }
+    if options
+        .free_cores
+        .map_or(false, |free_cores| free_cores < 0)
+    {
+        return Err("free_cores must not be negative".to_string());
+    }
+    if let Some(cpus) = &options.cpus {
+        parse_cpus(cpus).ok_or("cpus must look like 0-3,6")?;
+    }
+    for path in allowed_paths(options) {
+        if !std::path::Path::new(path).is_absolute() {
+            return Err(format!("{} is not an absolute path", path));
+        }
+    }
+    if options.sandbox && !cfg!(target_os = "linux") {
+        return Err("The engine sandbox is only available on Linux".to_string());
+    }
+    Ok(())
+}
+
+/// Applies the engine's options and placement to the command. The returned
+/// value must be kept until the command is spawned.
+#[cfg(target_os = "linux")]
+pub fn configure(
+    command: &mut Command,
+    binary_location: &str,
+    options: &EngineOptions,
+    placement: &Placement,
+) -> Result<Sandbox, Box<dyn Error>> {
+    use std::{io, mem, os::unix::process::CommandExt, path::Path};
+
+    let mut sandbox = Sandbox::default();
+    let mut filter = Vec::new();
+
+    if options.sandbox {
+        let engine_directory = Path::new(binary_location)
+            .parent()
+            .ok_or("The engine binary has no parent directory")?;
+
+        let mut paths = vec![(engine_directory, linux::READ_EXECUTE)];
+        paths.extend(
+            linux::SYSTEM_PATHS
+                .iter()
+                .map(|(path, access)| (Path::new(*path), *access)),
+        );
+        paths.extend(
+            allowed_paths(options)
+                .into_iter()
+                .map(|path| (Path::new(path), linux::READ_ONLY)),
+        );
+
+        sandbox.ruleset_fd = Some(linux::landlock_ruleset(&paths)?);
+        filter = linux::deny_sockets_filter()?;
+    }
+
+    let ruleset_fd = sandbox.ruleset_fd;
+    let nice = placement.nice;
+    let cpu_set = placement.cpus.as_deref().map(linux::cpu_set);
+    let memory_limit = options
+        .memory_limit_mb
+        .map(|megabytes| megabytes.max(1) as libc::rlim_t * 1024 * 1024);
+    let cpu_time_limit = options
+        .cpu_time_limit_secs
+        .map(|seconds| seconds.max(1) as libc::rlim_t);
+
+    // runs in the child between fork and exec, so it may only make syscalls:
+    // no allocating, no locking
+    let pre_exec = move || {
+        if let Some(nice) = nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(cpu_set) = &cpu_set {
+            let size = mem::size_of::<libc::cpu_set_t>();
+            if unsafe { libc::sched_setaffinity(0, size, cpu_set) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        if let Some(bytes) = memory_limit {
+            linux::set_rlimit(libc::RLIMIT_AS, bytes)?;
+        }
+        if let Some(seconds) = cpu_time_limit {
+            linux::set_rlimit(libc::RLIMIT_CPU, seconds)?;
+        }
+        if let Some(ruleset_fd) = ruleset_fd {
+            linux::restrict_self(ruleset_fd, &filter)?;
+        }
+        Ok(())
+    };
+    unsafe { command.pre_exec(pre_exec) };
+
+    Ok(sandbox)
+}
+
+/// Priority and limits work like on Linux, there is no sandbox
+#[cfg(all(unix, not(target_os = "linux")))]
+pub fn configure(
+    command: &mut Command,
+    _binary_location: &str,
+    options: &EngineOptions,
+    placement: &Placement,
+) -> Result<Sandbox, Box<dyn Error>> {
+    use std::{io, os::unix::process::CommandExt};
+
+    if options.sandbox {
+        return Err("The engine sandbox is only available on Linux".into());
+    }
+
+    let nice = placement.nice;
+    let limits = [
+        (
+            libc::RLIMIT_AS,
+            options
+                .memory_limit_mb
+                .map(|megabytes| megabytes.max(1) as libc::rlim_t * 1024 * 1024),
+        ),
+        (
+            libc::RLIMIT_CPU,
+            options
+                .cpu_time_limit_secs
+                .map(|seconds| seconds.max(1) as libc::rlim_t),
+        ),
+    ];
+
+    let pre_exec = move || {
+        if let Some(nice) = nice {
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } != 0 {
+                return Err(io::Error::last_os_error());
+            }
+        }
+        for (resource, limit) in limits {
+            if let Some(limit) = limit {
+                let limit = libc::rlimit {
+                    rlim_cur: limit,
+                    rlim_max: limit,
+                };
+                if unsafe { libc::setrlimit(resource, &limit) } != 0 {
+                    return Err(io::Error::last_os_error());
+                }
+            }
+        }
+        Ok(())
+    };
+    unsafe { command.pre_exec(pre_exec) };
+
+    Ok(Sandbox::default())
+}
+
+/// Niceness maps to a priority class, limits are not supported. Also hides
+/// the console window.
+#[cfg(windows)]
+pub fn configure(
+    command: &mut Command,
+    _binary_location: &str,
+    options: &EngineOptions,
+    placement: &Placement,
+) -> Result<Sandbox, Box<dyn Error>> {
+    use std::os::windows::process::CommandExt;
+
+    const CREATE_NO_WINDOW: u32 = 0x08000000;
+    const BELOW_NORMAL_PRIORITY_CLASS: u32 = 0x00004000;
+    const IDLE_PRIORITY_CLASS: u32 = 0x00000040;
+
+    if options.sandbox {
+        return Err("The engine sandbox is only available on Linux".into());
+    }
+    if options.memory_limit_mb.is_some() || options.cpu_time_limit_secs.is_some() {
+        tracing::warn!("Engine limits are not supported on Windows, ignoring them");
+    }
+
+    let priority_class = match placement.nice {
+        Some(nice) if nice >= 10 => IDLE_PRIORITY_CLASS,
+        Some(nice) if nice > 0 => BELOW_NORMAL_PRIORITY_CLASS,
+        _ => 0,
+    };
+    command.creation_flags(CREATE_NO_WINDOW | priority_class);
+
+    Ok(Sandbox::default())
+}
+
+#[cfg(target_os = "linux")]
+mod linux {
+    use std::{
+        error::Error,
+        ffi::CString,
+        io, mem,
+        os::{
+            raw::{c_int, c_long},
+            unix::{ffi::OsStrExt, io::RawFd},
+        },
+        path::Path,
+    };
+
+    // Landlock ABI 1, see linux/landlock.h
+    const RULE_PATH_BENEATH: c_int = 1;
+    const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+    const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+    const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+    /// Every kind of access ABI 1 knows, all of them denied unless allowed
+    const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+    /// Access that can be granted on a file rather than a directory
+    const ACCESS_FS_FILE: u64 = ACCESS_FS_EXECUTE | ACCESS_FS_READ_FILE | (1 << 1);
+
+    /// The type of `RLIMIT_*`, which glibc declares as an enum and musl as
+    /// an int
+    #[cfg(not(target_env = "musl"))]
+    pub type RlimitResource = libc::__rlimit_resource_t;
+    #[cfg(target_env = "musl")]
+    pub type RlimitResource = c_int;
+
+    pub const READ_ONLY: u64 = ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR;
+    pub const READ_EXECUTE: u64 = READ_ONLY | ACCESS_FS_EXECUTE;
+
+    /// What a dynamically linked engine needs to start and count the CPUs.
+    /// Paths that don't exist on this system are skipped.
+    pub const SYSTEM_PATHS: [(&str, u64); 8] = [
+        ("/usr", READ_EXECUTE),
+        ("/lib", READ_EXECUTE),
+        ("/lib64", READ_EXECUTE),
+        ("/etc/ld.so.cache", READ_ONLY),
+        ("/proc/cpuinfo", READ_ONLY),
+        ("/sys/devices/system/cpu", READ_ONLY),
+        ("/dev/null", READ_ONLY),
+        ("/dev/urandom", READ_ONLY),
+    ];
+
+    #[repr(C)]
+    struct RulesetAttr {
+        handled_access_fs: u64,
+    }
+
+    #[repr(C, packed)]
+    struct PathBeneathAttr {
+        allowed_access: u64,
+        parent_fd: i32,
+    }
+
+    #[cfg(target_arch = "x86_64")]
+    const AUDIT_ARCH: u32 = 0xC000_003E;
+    #[cfg(target_arch = "aarch64")]
+    const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+    /// Created before forking, so the child only has to apply it
+    pub fn landlock_ruleset(paths: &[(&Path, u64)]) -> Result<RawFd, Box<dyn Error>> {
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_ALL,
+        };
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_landlock_create_ruleset,
+                &attr as *const RulesetAttr,
+                mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if fd < 0 {
+            let e = io::Error::last_os_error();
+            return Err(match e.raw_os_error() {
+                Some(libc::ENOSYS) | Some(libc::EOPNOTSUPP) => {
+                    "The engine sandbox needs Landlock, which this kernel doesn't support or has disabled".into()
+                }
+                _ => format!("Failed to create the engine sandbox: {}", e).into(),
+            });
+        }
+        // the ruleset is created close-on-exec
+        let fd = fd as RawFd;
+
+        for (path, access) in paths {
+            let c_path = CString::new(path.as_os_str().as_bytes())?;
+            let parent_fd = unsafe { libc::open(c_path.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+            if parent_fd < 0 {
+                continue;
+            }
+
+            let allowed_access = if path.is_dir() {
+                *access
+            } else {
+                access & ACCESS_FS_FILE
+            };
+            let rule = PathBeneathAttr {
+                allowed_access,
+                parent_fd,
+            };
+            let result = unsafe {
+                libc::syscall(
+                    libc::SYS_landlock_add_rule,
+                    fd,
+                    RULE_PATH_BENEATH,
+                    &rule as *const PathBeneathAttr,
+                    0u32,
+                )
+            };
+            let e = io::Error::last_os_error();
+            unsafe { libc::close(parent_fd) };
+
+            if result < 0 {
+                unsafe { libc::close(fd) };
+                return Err(format!(
+                    "Failed to allow {} in the engine sandbox: {}",
+                    path.display(),
+                    e
+                )
+                .into());
+            }
+        }
+
+        Ok(fd)
+    }
+
+    fn bpf(code: u32, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+        libc::sock_filter {
+            code: code as u16,
+            jt,
+            jf,
+            k,
+        }
+    }
+
+    /// Syscalls that open sockets, directly or through an io_uring
+    /// (`IORING_OP_SOCKET`)
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const SOCKET_SYSCALLS: [c_long; 4] = [
+        libc::SYS_socket,
+        libc::SYS_io_uring_setup,
+        libc::SYS_io_uring_enter,
+        libc::SYS_io_uring_register,
+    ];
+
+    /// Set in the numbers of x32 syscalls, which pass the x86_64 arch check
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+    /// A seccomp program that makes [`SOCKET_SYSCALLS`] fail with EACCES, and
+    /// kills the process on syscalls of another ABI
+    #[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+    pub fn deny_sockets_filter() -> Result<Vec<libc::sock_filter>, Box<dyn Error>> {
+        // offsets in struct seccomp_data
+        const NR: u32 = 0;
+        const ARCH: u32 = 4;
+
+        let kill = bpf(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_KILL_PROCESS,
+            0,
+            0,
+        );
+
+        let mut filter = vec![
+            bpf(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, ARCH, 0, 0),
+            bpf(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                AUDIT_ARCH,
+                1,
+                0,
+            ),
+            kill,
+            bpf(libc::BPF_LD | libc::BPF_W | libc::BPF_ABS, NR, 0, 0),
+            bpf(
+                libc::BPF_JMP | libc::BPF_JGE | libc::BPF_K,
+                X32_SYSCALL_BIT,
+                0,
+                1,
+            ),
+            kill,
+        ];
+        // each match jumps over the remaining checks and the allow to EACCES
+        for (i, nr) in SOCKET_SYSCALLS.iter().enumerate() {
+            filter.push(bpf(
+                libc::BPF_JMP | libc::BPF_JEQ | libc::BPF_K,
+                *nr as u32,
+                (SOCKET_SYSCALLS.len() - i) as u8,
+                0,
+            ));
+        }
+        filter.push(bpf(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ALLOW,
+            0,
+            0,
+        ));
+        filter.push(bpf(
+            libc::BPF_RET | libc::BPF_K,
+            libc::SECCOMP_RET_ERRNO | libc::EACCES as u32,
+            0,
+            0,
+        ));
+
+        Ok(filter)
+    }
+
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn deny_sockets_filter() -> Result<Vec<libc::sock_filter>, Box<dyn Error>> {
+        Err("The engine sandbox is not available on this CPU architecture".into())
+    }
+
+    /// The cores the app itself may run on
+    fn allowed_cpus() -> Vec<usize> {
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        if unsafe { libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut set) } != 0 {
+            return Vec::new();
+        }
+
+        (0..mem::size_of::<libc::cpu_set_t>() * 8)
+            .filter(|cpu| unsafe { libc::CPU_ISSET(*cpu, &set) })
+            .collect()
+    }
+
+    /// The configured cores that exist, minus the highest numbered ones that
+    /// are left free. At least one core is kept. None if there's no need to
+    /// pin the engine.
+    pub fn pinned_cpus(cpus: Option<Vec<usize>>, free_cores: usize) -> Option<Vec<usize>> {
+        if cpus.is_none() && free_cores == 0 {
+            return None;
+        }
+
+        let allowed = allowed_cpus();
+        let mut cpus = match cpus {
+            Some(cpus) => cpus
+                .into_iter()
+                .filter(|cpu| allowed.contains(cpu))
+                .collect(),
+            None => allowed,
+        };
+        if cpus.is_empty() {
+            tracing::warn!("None of the configured engine cores exist, using all of them");
+            return None;
+        }
+
+        let keep = cpus.len().saturating_sub(free_cores).max(1);
+        cpus.truncate(keep);
+        Some(cpus)
+    }
+
+    /// Built before forking, as `CPU_SET` is not a syscall
+    pub fn cpu_set(cpus: &[usize]) -> libc::cpu_set_t {
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for cpu in cpus {
+            unsafe { libc::CPU_SET(*cpu, &mut set) };
+        }
+        set
+    }
+
+    pub fn set_rlimit(resource: RlimitResource, limit: libc::rlim_t) -> io::Result<()> {
+        let limit = libc::rlimit {
+            rlim_cur: limit,
+            rlim_max: limit,
+        };
+        match unsafe { libc::setrlimit(resource, &limit) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        }
+    }
+
+    /// Applies the sandbox to the calling process. Called between fork and
+    /// exec, so it only makes syscalls.
+    pub fn restrict_self(ruleset_fd: RawFd, filter: &[libc::sock_filter]) -> io::Result<()> {
+        let check = |result: c_long| match result {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        };
+
+        // required to apply either without privileges
+        check(unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) } as c_long)?;
+        check(unsafe { libc::syscall(libc::SYS_landlock_restrict_self, ruleset_fd, 0u32) })?;
+
+        let program = libc::sock_fprog {
+            len: filter.len() as u16,
+            filter: filter.as_ptr() as *mut libc::sock_filter,
+        };
+        check(unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                libc::SECCOMP_MODE_FILTER,
+                &program as *const libc::sock_fprog,
+            )
+        } as c_long)
+    }
+}
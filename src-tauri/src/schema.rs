@@ -1,9 +1,42 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    accounts (id) {
+        id -> Integer,
+        lichess_host -> Text,
+        engine_host -> Text,
+        username -> Text,
+        token_expires_at -> Nullable<BigInt>,
+        enabled -> Bool,
+    }
+}
+
+diesel::table! {
+    analysis_history (id) {
+        id -> Text,
+        session_id -> Text,
+        engine_id -> Text,
+        engine_name -> Text,
+        variant -> Text,
+        initial_fen -> Text,
+        moves -> Text,
+        lines -> Text,
+        created_at -> Timestamp,
+        submission_error -> Nullable<Text>,
+    }
+}
+
 diesel::table! {
     engines (engine_id) {
         engine_id -> Text,
         binary_location -> Text,
+        sandbox -> Bool,
+        memory_limit_mb -> Nullable<Integer>,
+        cpu_time_limit_secs -> Nullable<Integer>,
+        allowed_paths -> Text,
+        nice -> Nullable<Integer>,
+        cpus -> Nullable<Text>,
+        free_cores -> Nullable<Integer>,
     }
 }
 
@@ -14,4 +47,4 @@ diesel::table! {
     }
 }
 
-diesel::allow_tables_to_appear_in_same_query!(engines, settings,);
+diesel::allow_tables_to_appear_in_same_query!(accounts, analysis_history, engines, settings,);
@@ -0,0 +1,190 @@
+//! Storage for credentials (the Lichess token and the provider secret).
+//!
+//! Secrets go to the OS keyring (Secret Service on Linux, Keychain on macOS,
+//! Credential Manager on Windows). When no keyring is available, for example
+//! on a headless box without a Secret Service daemon, they go to an encrypted
+//! file in the app data directory instead.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use chacha20poly1305::{aead::Aead, ChaCha20Poly1305, Key, KeyInit, Nonce};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use tracing::warn;
+
+use crate::utils::get_app_data_dir;
+
+const KEYRING_SERVICE: &str = "lichess-tauri";
+
+pub trait SecretStore: Send + Sync {
+    fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>>;
+    fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>>;
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>>;
+}
+
+pub struct KeyringStore;
+
+impl KeyringStore {
+    /// Whether a keyring backend is actually reachable on this machine
+    fn is_available() -> bool {
+        match keyring::Entry::new(KEYRING_SERVICE, "availability_check")
+            .and_then(|entry| entry.get_password())
+        {
+            Ok(_) | Err(keyring::Error::NoEntry) => true,
+            Err(e) => {
+                warn!("OS keyring unavailable, using encrypted file: {}", e);
+                false
+            }
+        }
+    }
+}
+
+impl SecretStore for KeyringStore {
+    fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        match keyring::Entry::new(KEYRING_SERVICE, key)?.get_password() {
+            Ok(value) => Ok(Some(value)),
+            Err(keyring::Error::NoEntry) => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        keyring::Entry::new(KEYRING_SERVICE, key)?.set_password(value)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        match keyring::Entry::new(KEYRING_SERVICE, key)?.delete_password() {
+            Ok(_) | Err(keyring::Error::NoEntry) => Ok(()),
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+/// Secrets encrypted with ChaCha20-Poly1305 under a random key kept next to
+/// them (readable by the current user only). This keeps them out of the
+/// database and its backups, but can't protect against someone who can read
+/// the user's files.
+pub struct EncryptedFileStore {
+    path: PathBuf,
+    cipher: ChaCha20Poly1305,
+    lock: Mutex<()>,
+}
+
+impl EncryptedFileStore {
+    pub fn open(dir: &Path) -> Result<Self, Box<dyn Error>> {
+        let key_path = dir.join("secrets.key");
+
+        let key = match fs::read(&key_path) {
+            Ok(key) if key.len() == 32 => key,
+            Ok(_) => return Err("secrets.key is corrupted".into()),
+            Err(_) => {
+                let mut key = vec![0u8; 32];
+                rand::thread_rng().fill_bytes(&mut key);
+                fs::write(&key_path, &key)?;
+                restrict_permissions(&key_path)?;
+                key
+            }
+        };
+
+        Ok(EncryptedFileStore {
+            path: dir.join("secrets.bin"),
+            cipher: ChaCha20Poly1305::new(Key::from_slice(&key)),
+            lock: Mutex::new(()),
+        })
+    }
+
+    fn read(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(_) => return Ok(HashMap::new()),
+        };
+
+        if contents.len() < 12 {
+            return Err("secrets.bin is corrupted".into());
+        }
+
+        let (nonce, ciphertext) = contents.split_at(12);
+        let plaintext = self
+            .cipher
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| "Error decrypting secrets.bin")?;
+
+        Ok(serde_json::from_slice(&plaintext)?)
+    }
+
+    fn write(&self, secrets: &HashMap<String, String>) -> Result<(), Box<dyn Error>> {
+        let mut nonce = [0u8; 12];
+        rand::thread_rng().fill_bytes(&mut nonce);
+
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                serde_json::to_vec(secrets)?.as_ref(),
+            )
+            .map_err(|_| "Error encrypting secrets")?;
+
+        fs::write(&self.path, [nonce.as_slice(), &ciphertext].concat())?;
+        restrict_permissions(&self.path)?;
+
+        Ok(())
+    }
+}
+
+impl SecretStore for EncryptedFileStore {
+    fn get(&self, key: &str) -> Result<Option<String>, Box<dyn Error>> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.read()?.remove(key))
+    }
+
+    fn set(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let _guard = self.lock.lock().unwrap();
+        let mut secrets = self.read()?;
+        secrets.insert(key.to_string(), value.to_string());
+        self.write(&secrets)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let _guard = self.lock.lock().unwrap();
+        let mut secrets = self.read()?;
+        if secrets.remove(key).is_some() {
+            self.write(&secrets)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(target_family = "unix")]
+fn restrict_permissions(path: &Path) -> Result<(), Box<dyn Error>> {
+    use std::os::unix::prelude::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(())
+}
+
+#[cfg(target_family = "windows")]
+fn restrict_permissions(_path: &Path) -> Result<(), Box<dyn Error>> {
+    Ok(())
+}
+
+static STORE: Lazy<Box<dyn SecretStore>> = Lazy::new(|| {
+    if KeyringStore::is_available() {
+        Box::new(KeyringStore)
+    } else {
+        Box::new(
+            EncryptedFileStore::open(&get_app_data_dir())
+                .expect("Error opening encrypted secrets file"),
+        )
+    }
+});
+
+pub fn store() -> &'static dyn SecretStore {
+    &**STORE
+}
@@ -0,0 +1,562 @@
+//! Typed, validated settings.
+//!
+//! Settings are persisted as key/value strings by a [`SettingsBackend`] (the
+//! `settings` table by default) and parsed into [`Settings`] once, so the rest
+//! of the app reads plain fields from memory. Running subsystems can
+//! [`subscribe`] to be told about changes.
+//!
+//! Secrets are the exception: they live in [`secrets`] and are never
+//! serialized, so they don't reach the webview.
+
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt, fs,
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Receiver, Sender},
+        Mutex,
+    },
+};
+
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use reqwest::Url;
+use serde::Serialize;
+use tracing::{error, warn};
+
+use crate::{db, http, resource_policy, sandbox, secrets};
+
+/// Settings kept in secret storage instead of the settings backend
+const SECRET_KEYS: [&str; 2] = ["lichess_token", "provider_secret"];
+
+/// Settings written by logging in and by the accounts, never edited directly
+pub const ACCOUNT_KEYS: [&str; 4] = [
+    "lichess_token",
+    "provider_secret",
+    "lichess_username",
+    "lichess_token_expires_at",
+];
+
+pub const LOG_LEVELS: [&str; 5] = ["error", "warn", "info", "debug", "trace"];
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Settings {
+    pub lichess_host: String,
+    pub engine_host: String,
+    #[serde(skip_serializing)]
+    pub provider_secret: String,
+    #[serde(skip_serializing)]
+    pub lichess_token: Option<String>,
+    pub lichess_username: Option<String>,
+    /// Unix timestamp after which Lichess stops accepting the token
+    pub lichess_token_expires_at: Option<i64>,
+    /// Whether the worker provides analysis for this account
+    pub provider_enabled: bool,
+    /// Upper bound (in seconds) for the worker's backoff after a failed long
+    /// poll, unless Lichess asks to wait longer
+    pub max_backoff_secs: u64,
+    /// Machine-wide CPU usage (percent) above which fewer threads are used,
+    /// or none at all once every core is busy
+    pub busy_cpu_percent: Option<u64>,
+    /// Pause while less memory than this (in MB) is available
+    pub min_free_memory_mb: Option<u64>,
+    /// Local time range like `22:00-07:00` during which providing is paused
+    pub quiet_hours: Option<String>,
+    pub pause_on_battery: bool,
+    /// Niceness engines run with, unless set per engine
+    pub engine_nice: Option<i32>,
+    /// Cores engines run on, like `0-3,6`, unless set per engine
+    pub engine_cpus: Option<String>,
+    /// Cores left to other programs, unless set per engine
+    pub engine_free_cores: Option<u64>,
+    /// One of [`LOG_LEVELS`]
+    pub log_level: String,
+    /// HTTP or SOCKS proxy for all requests, instead of the system's
+    pub proxy: Option<String>,
+    /// PEM file with certificates to trust in addition to the system's
+    pub ca_certificates: Option<String>,
+    pub connect_timeout_secs: u64,
+    /// How long to wait for each read of a response
+    pub read_timeout_secs: u64,
+    /// Longest an engine may search for one request, whatever its limit
+    pub max_search_secs: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            lichess_host: "https://lichess.org".to_string(),
+            engine_host: "https://engine.lichess.ovh".to_string(),
+            provider_secret: String::new(),
+            lichess_token: None,
+            lichess_username: None,
+            lichess_token_expires_at: None,
+            provider_enabled: true,
+            max_backoff_secs: 10,
+            busy_cpu_percent: None,
+            min_free_memory_mb: None,
+            quiet_hours: None,
+            pause_on_battery: false,
+            engine_nice: None,
+            engine_cpus: None,
+            engine_free_cores: None,
+            log_level: "info".to_string(),
+            proxy: None,
+            ca_certificates: None,
+            connect_timeout_secs: 10,
+            read_timeout_secs: 30,
+            max_search_secs: 600,
+        }
+    }
+}
+
+impl Settings {
+    fn set(&mut self, key: &str, value: &str) -> Result<(), SettingsError> {
+        match key {
+            "lichess_host" => self.lichess_host = parse_url(key, value)?,
+            "engine_host" => self.engine_host = parse_url(key, value)?,
+            "provider_secret" => self.provider_secret = parse_non_empty(key, value)?,
+            "lichess_token" => self.lichess_token = Some(parse_non_empty(key, value)?),
+            "lichess_username" => self.lichess_username = Some(parse_non_empty(key, value)?),
+            "lichess_token_expires_at" => {
+                self.lichess_token_expires_at =
+                    Some(parse_range(key, value, 0, i64::MAX as u64)? as i64)
+            }
+            "provider_enabled" => self.provider_enabled = parse_bool(key, value)?,
+            "max_backoff_secs" => self.max_backoff_secs = parse_range(key, value, 1, 300)?,
+            "busy_cpu_percent" => self.busy_cpu_percent = Some(parse_range(key, value, 1, 100)?),
+            "min_free_memory_mb" => {
+                self.min_free_memory_mb = Some(parse_range(key, value, 0, 1 << 20)?)
+            }
+            "quiet_hours" => {
+                resource_policy::parse_quiet_hours(value)
+                    .ok_or_else(|| invalid(key, "must look like 22:00-07:00"))?;
+                self.quiet_hours = Some(value.trim().to_string());
+            }
+            "pause_on_battery" => self.pause_on_battery = parse_bool(key, value)?,
+            "engine_nice" => self.engine_nice = Some(parse_range(key, value, 0, 19)? as i32),
+            "engine_cpus" => {
+                sandbox::parse_cpus(value).ok_or_else(|| invalid(key, "must look like 0-3,6"))?;
+                self.engine_cpus = Some(value.trim().to_string());
+            }
+            "engine_free_cores" => self.engine_free_cores = Some(parse_range(key, value, 0, 1024)?),
+            "log_level" => {
+                let level = value.trim().to_ascii_lowercase();
+                if !LOG_LEVELS.contains(&level.as_str()) {
+                    return Err(invalid(
+                        key,
+                        format!("must be one of {}", LOG_LEVELS.join(", ")),
+                    ));
+                }
+                self.log_level = level;
+            }
+            "proxy" => {
+                http::parse_proxy(value).map_err(|e| invalid(key, e))?;
+                self.proxy = Some(value.trim().to_string());
+            }
+            "ca_certificates" => {
+                http::read_certificates(value.trim()).map_err(|e| invalid(key, e))?;
+                self.ca_certificates = Some(value.trim().to_string());
+            }
+            "connect_timeout_secs" => self.connect_timeout_secs = parse_range(key, value, 1, 300)?,
+            "read_timeout_secs" => self.read_timeout_secs = parse_range(key, value, 1, 3600)?,
+            "max_search_secs" => self.max_search_secs = parse_range(key, value, 1, 86400)?,
+            _ => return Err(SettingsError::UnknownKey(key.to_string())),
+        }
+
+        Ok(())
+    }
+
+    fn reset(&mut self, key: &str) -> Result<(), SettingsError> {
+        let defaults = Settings::default();
+
+        match key {
+            "lichess_host" => self.lichess_host = defaults.lichess_host,
+            "engine_host" => self.engine_host = defaults.engine_host,
+            "provider_secret" => self.provider_secret = defaults.provider_secret,
+            "lichess_token" => self.lichess_token = defaults.lichess_token,
+            "lichess_username" => self.lichess_username = defaults.lichess_username,
+            "lichess_token_expires_at" => {
+                self.lichess_token_expires_at = defaults.lichess_token_expires_at
+            }
+            "provider_enabled" => self.provider_enabled = defaults.provider_enabled,
+            "max_backoff_secs" => self.max_backoff_secs = defaults.max_backoff_secs,
+            "busy_cpu_percent" => self.busy_cpu_percent = defaults.busy_cpu_percent,
+            "min_free_memory_mb" => self.min_free_memory_mb = defaults.min_free_memory_mb,
+            "quiet_hours" => self.quiet_hours = defaults.quiet_hours,
+            "pause_on_battery" => self.pause_on_battery = defaults.pause_on_battery,
+            "engine_nice" => self.engine_nice = defaults.engine_nice,
+            "engine_cpus" => self.engine_cpus = defaults.engine_cpus,
+            "engine_free_cores" => self.engine_free_cores = defaults.engine_free_cores,
+            "log_level" => self.log_level = defaults.log_level,
+            "proxy" => self.proxy = defaults.proxy,
+            "ca_certificates" => self.ca_certificates = defaults.ca_certificates,
+            "connect_timeout_secs" => self.connect_timeout_secs = defaults.connect_timeout_secs,
+            "read_timeout_secs" => self.read_timeout_secs = defaults.read_timeout_secs,
+            "max_search_secs" => self.max_search_secs = defaults.max_search_secs,
+            _ => return Err(SettingsError::UnknownKey(key.to_string())),
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub enum SettingsError {
+    UnknownKey(String),
+    Invalid { key: String, reason: String },
+    Backend(String),
+}
+
+impl fmt::Display for SettingsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SettingsError::UnknownKey(key) => write!(f, "Unknown setting {}", key),
+            SettingsError::Invalid { key, reason } => write!(f, "Invalid {}: {}", key, reason),
+            SettingsError::Backend(e) => write!(f, "Error saving settings: {}", e),
+        }
+    }
+}
+
+impl Error for SettingsError {}
+
+fn invalid(key: &str, reason: impl ToString) -> SettingsError {
+    SettingsError::Invalid {
+        key: key.to_string(),
+        reason: reason.to_string(),
+    }
+}
+
+pub fn parse_url(key: &str, value: &str) -> Result<String, SettingsError> {
+    let url = Url::parse(value.trim()).map_err(|e| invalid(key, e))?;
+
+    if url.scheme() != "http" && url.scheme() != "https" {
+        return Err(invalid(key, "must be an http or https URL"));
+    }
+
+    Ok(url.as_str().trim_end_matches('/').to_string())
+}
+
+fn parse_non_empty(key: &str, value: &str) -> Result<String, SettingsError> {
+    match value.trim() {
+        "" => Err(invalid(key, "must not be empty")),
+        value => Ok(value.to_string()),
+    }
+}
+
+pub fn parse_bool(key: &str, value: &str) -> Result<bool, SettingsError> {
+    match value.trim() {
+        "true" | "1" => Ok(true),
+        "false" | "0" => Ok(false),
+        _ => Err(invalid(key, "must be true or false")),
+    }
+}
+
+fn parse_range(key: &str, value: &str, min: u64, max: u64) -> Result<u64, SettingsError> {
+    let number = value.trim().parse::<u64>().map_err(|e| invalid(key, e))?;
+
+    if number < min || number > max {
+        return Err(invalid(key, format!("must be between {} and {}", min, max)));
+    }
+
+    Ok(number)
+}
+
+/// Where the raw key/value pairs are stored
+pub trait SettingsBackend: Send {
+    fn load(&self) -> Result<HashMap<String, String>, Box<dyn Error>>;
+    fn save(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>>;
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>>;
+
+    /// Whether secrets found here were left by an older version and should
+    /// move to secret storage. Files the user edits keep theirs.
+    fn moves_secrets(&self) -> bool {
+        true
+    }
+}
+
+/// The `settings` table of the app database
+pub struct SqliteBackend;
+
+impl SettingsBackend for SqliteBackend {
+    fn load(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        Ok(db::get_all_settings()
+            .into_iter()
+            .map(|setting| (setting.key, setting.value))
+            .collect())
+    }
+
+    fn save(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        db::update_setting(key, value);
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        db::delete_setting(key);
+        Ok(())
+    }
+}
+
+/// A TOML file of top-level `key = "value"` pairs, for headless machines.
+/// Tables (like `[engines]`) are left untouched, and so are secrets set in the
+/// file: they are read from it but saved to secret storage.
+pub struct TomlBackend {
+    path: PathBuf,
+}
+
+impl TomlBackend {
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        TomlBackend { path: path.into() }
+    }
+
+    fn read(&self) -> Result<toml::value::Table, Box<dyn Error>> {
+        if !self.path.exists() {
+            return Ok(toml::value::Table::new());
+        }
+
+        match toml::from_str(&fs::read_to_string(&self.path)?)? {
+            toml::Value::Table(table) => Ok(table),
+            _ => Err("expected a table".into()),
+        }
+    }
+
+    fn write(&self, table: toml::value::Table) -> Result<(), Box<dyn Error>> {
+        fs::write(&self.path, toml::to_string(&toml::Value::Table(table))?)?;
+        Ok(())
+    }
+}
+
+impl SettingsBackend for TomlBackend {
+    fn load(&self) -> Result<HashMap<String, String>, Box<dyn Error>> {
+        Ok(self
+            .read()?
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                toml::Value::String(value) => Some((key, value)),
+                toml::Value::Integer(_) | toml::Value::Float(_) | toml::Value::Boolean(_) => {
+                    Some((key, value.to_string()))
+                }
+                _ => None,
+            })
+            .collect())
+    }
+
+    fn save(&self, key: &str, value: &str) -> Result<(), Box<dyn Error>> {
+        let mut table = self.read()?;
+        table.insert(key.to_string(), toml::Value::String(value.to_string()));
+        self.write(table)
+    }
+
+    fn delete(&self, key: &str) -> Result<(), Box<dyn Error>> {
+        let mut table = self.read()?;
+        table.remove(key);
+        self.write(table)
+    }
+
+    fn moves_secrets(&self) -> bool {
+        false
+    }
+}
+
+struct Store {
+    backend: Box<dyn SettingsBackend>,
+    settings: Settings,
+    subscribers: Vec<Sender<Settings>>,
+}
+
+impl Store {
+    fn open(backend: Box<dyn SettingsBackend>) -> Store {
+        let mut settings = Settings::default();
+
+        let mut rows = backend.load().unwrap_or_else(|e| {
+            error!("Error loading settings, using defaults: {}", e);
+            HashMap::new()
+        });
+
+        for key in SECRET_KEYS {
+            let value = match rows.remove(key) {
+                // stored in plain text by an older version, move it out
+                Some(value) if backend.moves_secrets() => {
+                    match secrets::store().set(key, &value) {
+                        Ok(_) => {
+                            if let Err(e) = backend.delete(key) {
+                                warn!("Error removing plain text {}: {}", key, e);
+                            }
+                        }
+                        Err(e) => error!("Error moving {} to secret storage: {}", key, e),
+                    }
+                    Some(value)
+                }
+                Some(value) => Some(value),
+                None => secrets::store().get(key).unwrap_or_else(|e| {
+                    error!("Error reading {} from secret storage: {}", key, e);
+                    None
+                }),
+            };
+
+            if let Some(value) = value {
+                if let Err(e) = settings.set(key, &value) {
+                    warn!("Ignoring stored setting: {}", e);
+                }
+            }
+        }
+
+        // Rows written before settings were validated may not parse anymore.
+        // Those fall back to their default instead of breaking the app.
+        for (key, value) in rows {
+            if let Err(e) = settings.set(&key, &value) {
+                warn!("Ignoring stored setting: {}", e);
+            }
+        }
+
+        if settings.provider_secret.is_empty() {
+            settings.provider_secret = generate_secret();
+            if let Err(e) = secrets::store().set("provider_secret", &settings.provider_secret) {
+                error!("Error saving provider secret: {}", e);
+            }
+        }
+
+        Store {
+            backend,
+            settings,
+            subscribers: Vec::new(),
+        }
+    }
+
+    fn replace(&mut self, settings: Settings) {
+        self.settings = settings;
+
+        let settings = &self.settings;
+        self.subscribers
+            .retain(|subscriber| subscriber.send(settings.clone()).is_ok());
+    }
+}
+
+static STORE: Lazy<Mutex<Store>> = Lazy::new(|| Mutex::new(Store::open(Box::new(SqliteBackend))));
+
+/// 32 random bytes, hex encoded, like the init migration generates
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 32];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Switches where settings are stored, reloading them from the new backend
+pub fn use_backend(backend: Box<dyn SettingsBackend>) {
+    let opened = Store::open(backend);
+
+    let mut store = STORE.lock().unwrap();
+    store.backend = opened.backend;
+    store.replace(opened.settings);
+}
+
+pub fn get() -> Settings {
+    STORE.lock().unwrap().settings.clone()
+}
+
+pub fn update(key: &str, value: &str) -> Result<(), SettingsError> {
+    let mut store = STORE.lock().unwrap();
+
+    let mut settings = store.settings.clone();
+    settings.set(key, value)?;
+
+    if SECRET_KEYS.contains(&key) {
+        secrets::store().set(key, value.trim())
+    } else {
+        store.backend.save(key, value.trim())
+    }
+    .map_err(|e| SettingsError::Backend(e.to_string()))?;
+    store.replace(settings);
+
+    Ok(())
+}
+
+/// Removes a stored value so the setting goes back to its default
+pub fn delete(key: &str) -> Result<(), SettingsError> {
+    let mut store = STORE.lock().unwrap();
+
+    let mut settings = store.settings.clone();
+    settings.reset(key)?;
+
+    if SECRET_KEYS.contains(&key) {
+        secrets::store().delete(key)
+    } else {
+        store.backend.delete(key)
+    }
+    .map_err(|e| SettingsError::Backend(e.to_string()))?;
+    store.replace(settings);
+
+    Ok(())
+}
+
+/// Receives a copy of the settings every time one of them changes
+pub fn subscribe() -> Receiver<Settings> {
+    let (tx, rx) = mpsc::channel();
+    STORE.lock().unwrap().subscribers.push(tx);
+    rx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalizes_urls() {
+        let mut settings = Settings::default();
+
+        settings
+            .set("lichess_host", " http://localhost:8080/ ")
+            .unwrap();
+        assert_eq!(settings.lichess_host, "http://localhost:8080");
+
+        assert!(settings.set("lichess_host", "ftp://lichess.org").is_err());
+        assert!(settings.set("engine_host", "engine.lichess.ovh").is_err());
+        assert_eq!(settings.lichess_host, "http://localhost:8080");
+        assert_eq!(settings.engine_host, "https://engine.lichess.ovh");
+    }
+
+    #[test]
+    fn checks_ranges() {
+        let mut settings = Settings::default();
+
+        settings.set("max_backoff_secs", "300").unwrap();
+        settings.set("engine_nice", " 19 ").unwrap();
+        assert_eq!(settings.max_backoff_secs, 300);
+        assert_eq!(settings.engine_nice, Some(19));
+
+        for (key, value) in [
+            ("max_backoff_secs", "0"),
+            ("max_backoff_secs", "301"),
+            ("busy_cpu_percent", "-5"),
+            ("engine_nice", "20"),
+            ("read_timeout_secs", "soon"),
+        ] {
+            match settings.set(key, value) {
+                Err(SettingsError::Invalid { key: invalid, .. }) => assert_eq!(invalid, key),
+                result => panic!("{} = {} gave {:?}", key, value, result),
+            }
+        }
+        assert_eq!(settings.max_backoff_secs, 300);
+        assert_eq!(settings.busy_cpu_percent, None);
+    }
+
+    #[test]
+    fn checks_other_values() {
+        let mut settings = Settings::default();
+
+        settings.set("provider_enabled", "0").unwrap();
+        settings.set("log_level", "DEBUG").unwrap();
+        assert!(!settings.provider_enabled);
+        assert_eq!(settings.log_level, "debug");
+
+        assert!(settings.set("provider_enabled", "yes").is_err());
+        assert!(settings.set("log_level", "verbose").is_err());
+        assert!(settings.set("quiet_hours", "22-7").is_err());
+        assert!(settings.set("provider_secret", "  ").is_err());
+        assert!(matches!(
+            settings.set("lichess_hostname", "https://lichess.org"),
+            Err(SettingsError::UnknownKey(_))
+        ));
+    }
+}
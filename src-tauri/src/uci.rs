@@ -0,0 +1,202 @@
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Score {
+    Cp(i32),
+    Mate(i32),
+}
+
+/// A parsed `info` line from the engine's stdout.
+/// Only the fields we use are kept, everything else is skipped.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Info {
+    pub depth: Option<u32>,
+    pub multi_pv: Option<u32>,
+    pub score: Option<Score>,
+    /// Set when the score is only a `lowerbound` or `upperbound`
+    pub bound: bool,
+    pub nodes: Option<u64>,
+    pub nps: Option<u64>,
+    pub time: Option<u64>,
+    pub pv: Vec<String>,
+}
+
+impl Info {
+    pub fn parse(line: &str) -> Option<Info> {
+        let mut tokens = line.split_whitespace();
+
+        if tokens.next() != Some("info") {
+            return None;
+        }
+
+        let mut info = Info::default();
+
+        while let Some(token) = tokens.next() {
+            match token {
+                "depth" => info.depth = tokens.next().and_then(|v| v.parse().ok()),
+                "multipv" => info.multi_pv = tokens.next().and_then(|v| v.parse().ok()),
+                "nodes" => info.nodes = tokens.next().and_then(|v| v.parse().ok()),
+                "nps" => info.nps = tokens.next().and_then(|v| v.parse().ok()),
+                "time" => info.time = tokens.next().and_then(|v| v.parse().ok()),
+                "score" => {
+                    let kind = tokens.next();
+                    let value = tokens.next().and_then(|v| v.parse().ok());
+                    info.score = match (kind, value) {
+                        (Some("cp"), Some(cp)) => Some(Score::Cp(cp)),
+                        (Some("mate"), Some(mate)) => Some(Score::Mate(mate)),
+                        _ => None,
+                    };
+                }
+                "lowerbound" | "upperbound" => info.bound = true,
+                "pv" => {
+                    info.pv = tokens.by_ref().map(String::from).collect();
+                }
+                // free-form text runs to the end of the line
+                "string" => break,
+                "seldepth" | "hashfull" | "tbhits" | "sbhits" | "cpuload" | "currmove"
+                | "currmovenumber" => {
+                    tokens.next();
+                }
+                _ => {}
+            }
+        }
+
+        Some(info)
+    }
+
+    /// Whether this line is a complete evaluation of a principal variation,
+    /// as opposed to a progress update or a bound from an aspiration window
+    pub fn is_complete_line(&self) -> bool {
+        self.score.is_some() && !self.bound && !self.pv.is_empty()
+    }
+}
+
+/// A parsed `option` line from the engine's reply to `uci`
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EngineOption {
+    pub name: String,
+    /// `check`, `spin`, `combo`, `button` or `string`
+    pub kind: String,
+    pub default: Option<String>,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    /// Choices of a `combo` option
+    pub vars: Vec<String>,
+}
+
+impl EngineOption {
+    pub fn parse(line: &str) -> Option<EngineOption> {
+        let line = line.strip_prefix("option ")?;
+        let mut option = EngineOption::default();
+
+        // names and values may contain spaces, so collect words until the
+        // next keyword
+        let mut field = None;
+        let mut value: Vec<&str> = Vec::new();
+
+        for token in line.split_whitespace().chain(std::iter::once("var")) {
+            match token {
+                "name" | "type" | "default" | "min" | "max" | "var" => {
+                    let text = value.join(" ");
+                    match field {
+                        Some("name") => option.name = text,
+                        Some("type") => option.kind = text,
+                        Some("default") => option.default = Some(text),
+                        Some("min") => option.min = text.parse().ok(),
+                        Some("max") => option.max = text.parse().ok(),
+                        Some("var") => option.vars.push(text),
+                        _ => {}
+                    }
+                    field = Some(token);
+                    value.clear();
+                }
+                _ => value.push(token),
+            }
+        }
+
+        if option.name.is_empty() {
+            return None;
+        }
+
+        Some(option)
+    }
+}
+
+/// Parses an `id name ...` or `id author ...` line into its key and value
+pub fn parse_id(line: &str) -> Option<(&str, &str)> {
+    let line = line.strip_prefix("id ")?;
+    let (key, value) = line.split_once(' ')?;
+    Some((key, value.trim()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_a_principal_variation() {
+        let info = Info::parse(
+            "info depth 20 seldepth 28 multipv 2 score cp -35 nodes 123456 nps 987654 \
+             hashfull 12 tbhits 0 time 125 pv e2e4 e7e5 g1f3",
+        )
+        .unwrap();
+
+        assert_eq!(info.depth, Some(20));
+        assert_eq!(info.multi_pv, Some(2));
+        assert_eq!(info.score, Some(Score::Cp(-35)));
+        assert_eq!(info.nodes, Some(123456));
+        assert_eq!(info.nps, Some(987654));
+        assert_eq!(info.time, Some(125));
+        assert_eq!(info.pv, ["e2e4", "e7e5", "g1f3"]);
+        assert!(info.is_complete_line());
+    }
+
+    #[test]
+    fn marks_bounds_as_incomplete() {
+        let info = Info::parse("info depth 5 score mate -3 lowerbound pv h7h8q").unwrap();
+
+        assert_eq!(info.score, Some(Score::Mate(-3)));
+        assert!(info.bound);
+        assert!(!info.is_complete_line());
+    }
+
+    #[test]
+    fn skips_progress_and_text() {
+        let info = Info::parse("info depth 12 currmove e2e4 currmovenumber 1").unwrap();
+        assert_eq!(info.depth, Some(12));
+        assert!(info.pv.is_empty());
+        assert!(!info.is_complete_line());
+
+        let info = Info::parse("info string NNUE evaluation using nn.nnue pv enabled").unwrap();
+        assert!(info.pv.is_empty());
+
+        assert!(Info::parse("bestmove e2e4 ponder e7e5").is_none());
+    }
+
+    #[test]
+    fn parses_options() {
+        let hash = EngineOption::parse("option name Hash type spin default 16 min 1 max 33554432")
+            .unwrap();
+        assert_eq!(hash.name, "Hash");
+        assert_eq!(hash.kind, "spin");
+        assert_eq!(hash.default.as_deref(), Some("16"));
+        assert_eq!((hash.min, hash.max), (Some(1), Some(33554432)));
+
+        let variant = EngineOption::parse(
+            "option name UCI_Variant type combo default chess var chess var king of the hill",
+        )
+        .unwrap();
+        assert_eq!(variant.vars, ["chess", "king of the hill"]);
+
+        let clear_hash = EngineOption::parse("option name Clear Hash type button").unwrap();
+        assert_eq!(clear_hash.name, "Clear Hash");
+        assert_eq!(clear_hash.kind, "button");
+        assert_eq!(clear_hash.default, None);
+
+        assert!(EngineOption::parse("option type check default false").is_none());
+        assert!(EngineOption::parse("id name Stockfish 16").is_none());
+    }
+}
@@ -0,0 +1,171 @@
+//! Enforces the limits of a search locally, for engines that overshoot
+//! `go movetime`, ignore `go nodes`, or search a fortress to a depth they
+//! never reach.
+
+use std::time::{Duration, Instant};
+
+use crate::{lichess::Search, uci};
+
+/// How far past its movetime a search may run, on top of [`MOVETIME_GRACE`]
+const MOVETIME_TOLERANCE: f64 = 0.1;
+const MOVETIME_GRACE: Duration = Duration::from_secs(1);
+/// Plies past the requested depth
+const DEPTH_TOLERANCE: u32 = 1;
+/// Share of the requested nodes searched on top, as engines only report
+/// nodes every so often
+const NODES_TOLERANCE: f64 = 0.1;
+/// How long an engine gets to answer `stop` with `bestmove`
+const STOP_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub enum Action {
+    Continue,
+    /// Send `stop`, the search went past its limit
+    Stop(String),
+    /// The engine ignored `stop`
+    Kill,
+}
+
+pub struct Watchdog {
+    search: Search,
+    max_time: Duration,
+    started: Instant,
+    depth: u32,
+    nodes: u64,
+    stop_sent: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Starts timing a search, which may not run for longer than `max_time`
+    /// whatever its limit
+    pub fn start(search: &Search, max_time: Duration) -> Watchdog {
+        Watchdog {
+            search: search.clone(),
+            max_time,
+            started: Instant::now(),
+            depth: 0,
+            nodes: 0,
+            stop_sent: None,
+        }
+    }
+
+    /// Keeps the progress reported by an `info` line
+    pub fn update(&mut self, info: &uci::Info) {
+        self.depth = self.depth.max(info.depth.unwrap_or_default());
+        self.nodes = self.nodes.max(info.nodes.unwrap_or_default());
+    }
+
+    /// What to do with the engine now. Called after each line of output,
+    /// and regularly while the engine is silent.
+    pub fn check(&mut self) -> Action {
+        if let Some(stop_sent) = self.stop_sent {
+            if stop_sent.elapsed() >= STOP_TIMEOUT {
+                return Action::Kill;
+            }
+            return Action::Continue;
+        }
+
+        match self.exceeded() {
+            Some(reason) => {
+                self.stop_sent = Some(Instant::now());
+                Action::Stop(reason)
+            }
+            None => Action::Continue,
+        }
+    }
+
+    fn exceeded(&self) -> Option<String> {
+        let elapsed = self.started.elapsed();
+        if elapsed >= self.max_time {
+            return Some(format!(
+                "searched for the maximum of {}s",
+                self.max_time.as_secs()
+            ));
+        }
+
+        match self.search {
+            Search::Movetime(movetime) => {
+                let movetime = Duration::from_millis(u64::from(movetime));
+                let allowed = movetime.mul_f64(1.0 + MOVETIME_TOLERANCE) + MOVETIME_GRACE;
+                (elapsed > allowed).then(|| {
+                    format!(
+                        "searched for {:.1}s instead of {:.1}s",
+                        elapsed.as_secs_f64(),
+                        movetime.as_secs_f64()
+                    )
+                })
+            }
+            Search::Depth(depth) => (self.depth > depth + DEPTH_TOLERANCE)
+                .then(|| format!("reached depth {} instead of {}", self.depth, depth)),
+            Search::Nodes(nodes) => (self.nodes as f64 > nodes as f64 * (1.0 + NODES_TOLERANCE))
+                .then(|| format!("searched {} nodes instead of {}", self.nodes, nodes)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NO_MAX: Duration = Duration::from_secs(3600);
+
+    fn ago(duration: Duration) -> Instant {
+        Instant::now().checked_sub(duration).unwrap()
+    }
+
+    fn progress(depth: u32, nodes: u64) -> uci::Info {
+        uci::Info {
+            depth: Some(depth),
+            nodes: Some(nodes),
+            ..uci::Info::default()
+        }
+    }
+
+    #[test]
+    fn stops_an_overlong_search_then_kills_the_engine() {
+        let mut watchdog = Watchdog::start(&Search::Movetime(1000), NO_MAX);
+        assert!(matches!(watchdog.check(), Action::Continue));
+
+        // 1s, plus 10%, plus the grace second
+        watchdog.started = ago(Duration::from_millis(2000));
+        assert!(matches!(watchdog.check(), Action::Continue));
+
+        watchdog.started = ago(Duration::from_millis(2200));
+        match watchdog.check() {
+            Action::Stop(reason) => assert!(reason.ends_with("instead of 1.0s"), "{}", reason),
+            _ => panic!("expected the search to be stopped"),
+        }
+
+        // waits for `bestmove` before killing
+        assert!(matches!(watchdog.check(), Action::Continue));
+        watchdog.stop_sent = Some(ago(STOP_TIMEOUT - Duration::from_millis(100)));
+        assert!(matches!(watchdog.check(), Action::Continue));
+        watchdog.stop_sent = Some(ago(STOP_TIMEOUT));
+        assert!(matches!(watchdog.check(), Action::Kill));
+    }
+
+    #[test]
+    fn stops_past_the_depth_or_nodes() {
+        let mut watchdog = Watchdog::start(&Search::Depth(10), NO_MAX);
+        watchdog.update(&progress(11, 0));
+        assert!(matches!(watchdog.check(), Action::Continue));
+        watchdog.update(&progress(12, 0));
+        assert!(matches!(watchdog.check(), Action::Stop(_)));
+
+        let mut watchdog = Watchdog::start(&Search::Nodes(1000), NO_MAX);
+        watchdog.update(&progress(1, 1100));
+        assert!(matches!(watchdog.check(), Action::Continue));
+        watchdog.update(&progress(1, 1101));
+        assert!(matches!(watchdog.check(), Action::Stop(_)));
+    }
+
+    #[test]
+    fn stops_at_the_maximum_time_whatever_the_limit() {
+        let mut watchdog = Watchdog::start(&Search::Depth(60), Duration::from_secs(10));
+        watchdog.started = ago(Duration::from_secs(10));
+
+        match watchdog.check() {
+            Action::Stop(reason) => assert_eq!(reason, "searched for the maximum of 10s"),
+            _ => panic!("expected the search to be stopped"),
+        }
+    }
+}
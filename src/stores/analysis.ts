@@ -8,9 +8,19 @@ export interface LichessWorkEvent {
   event: string
   windowLabel: string
   payload: {
-    event: 'Sleep' | 'Status' | 'Uci'
+    event:
+      | 'Sleep'
+      | 'Status'
+      | 'Uci'
+      | 'NoWork'
+      | 'Unauthorized'
+      | 'RateLimited'
+      | 'ServerError'
+      | 'ConnectionLost'
+      | 'Offline'
     message: string
     analysis_request: false
+    account_id?: number
   }
 }
 
@@ -23,9 +33,17 @@ export interface LichessStatusEvent {
   payload: {
     status: string
     level: StatusLevel
+    account_id?: number
   }
 }
 
+// shown while the worker waits to poll Lichess again
+const retryStatuses: Record<string, string> = {
+  RateLimited: 'Lichess asked to slow down, retrying',
+  ServerError: 'Lichess is having trouble, retrying',
+  ConnectionLost: 'Cannot reach Lichess, retrying',
+}
+
 type ChessColor = 'w' | 'b'
 type UciScoreType = 'cp' | 'mate'
 
@@ -116,6 +134,18 @@ export const useAnalysisStore = defineStore('analysis', {
       } else if (event.payload.event === 'Sleep') {
         this.status = 'Sleeping'
         this.sleepDuration = parseInt(event.payload.message)
+      } else if (event.payload.event === 'NoWork') {
+        this.status = 'Waiting for moves'
+      } else if (event.payload.event === 'Offline') {
+        this.status = 'Offline, waiting for the network'
+        this.statusLevel = 'Warn'
+      } else if (event.payload.event === 'Unauthorized') {
+        this.status = event.payload.message
+        this.statusLevel = 'Error'
+      } else if (event.payload.event in retryStatuses) {
+        this.status = retryStatuses[event.payload.event]
+        this.statusLevel = 'Warn'
+        this.sleepDuration = parseInt(event.payload.message)
       } else if (event.payload.event === 'Uci') {
         let output = parseUciString(event.payload.message)
         if (!output.multipv || output.multipv === '1') {
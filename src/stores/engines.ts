@@ -1,5 +1,5 @@
+import { invoke } from '@tauri-apps/api'
 import { defineStore } from 'pinia'
-import { useSettingsStore } from './settings'
 
 type Variant =
   | 'chess'
@@ -30,6 +30,15 @@ export type LichessEngine = {
   clientSecret: string
 }
 
+export type EngineTest = {
+  name?: string
+  author?: string
+  options: { name: string }[]
+  best_move: string
+  nps?: number
+  stderr: string
+}
+
 export const useEnginesStore = defineStore('engines', {
   state: () => {
     return {
@@ -45,19 +54,8 @@ export const useEnginesStore = defineStore('engines', {
 })
 
 async function getUserEnginesFromLichess(): Promise<LichessEngine[]> {
-  const settings = useSettingsStore()
-  let url = `${settings.lichessHost}/api/external-engine`
-  return await fetch(url, {
-    headers: {
-      Authorization: `Bearer ${settings.lichess_token}`,
-    },
-  }).then<LichessEngine[]>(async (response) => {
-    if (response.status === 401) {
-      await settings.logout()
-      throw new Error('Unauthorized')
-    }
-    return response.json()
-  })
+  // an expired token logs the account out on the Rust side
+  return await invoke<LichessEngine[]>('list_engines')
 }
 
 export function refreshEngineList(): void {
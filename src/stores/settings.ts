@@ -6,15 +6,13 @@ export const useSettingsStore = defineStore('settings', {
     return {
       lichessHost: '',
       engineHost: '',
-      providerSecret: '',
 
       lichess_username: '',
-      lichess_token: '',
     }
   },
   getters: {
     isLoggedIn(): boolean {
-      return !!this.lichess_token
+      return !!this.lichess_username
     },
   },
   actions: {
@@ -1,58 +1,38 @@
-import { NewEngine, LichessEngine } from '../stores/engines'
-import { useSettingsStore } from '../stores/settings'
+import { invoke } from '@tauri-apps/api'
+import { EngineTest, NewEngine, LichessEngine } from '../stores/engines'
 
-export async function saveEngineToLichess(
+export async function registerEngine(
   engine: NewEngine,
-  id?: string
+  binaryLocation: string
 ): Promise<LichessEngine> {
-  const settings = useSettingsStore()
-
-  let method = 'POST'
-  let url = `${settings.lichessHost}/api/external-engine`
+  return await invoke<LichessEngine>('register_engine', {
+    engine,
+    binaryLocation,
+  })
+}
 
-  if (id) {
-    method = 'PUT'
-    url = `${url}/${id}`
-  }
+export async function updateEngine(
+  engineId: string,
+  engine: NewEngine
+): Promise<LichessEngine> {
+  return await invoke<LichessEngine>('update_engine', { engineId, engine })
+}
 
-  return await fetch(url, {
-    method,
-    headers: {
-      Authorization: `Bearer ${settings.lichess_token}`,
-      'Content-Type': 'application/json',
-    },
-    body: JSON.stringify({
-      name: engine.name,
-      maxThreads: engine.maxThreads,
-      maxHash: engine.maxHash,
-      variants: engine.variants,
-      providerSecret: settings.providerSecret,
-    }),
-  }).then<LichessEngine>(async (response) => {
-    if (!response.ok) {
-      throw await response.json()
-    }
+export async function deleteEngine(engine: LichessEngine): Promise<void> {
+  await invoke('delete_engine', { engineId: engine.id })
+}
 
-    return response.json()
-  })
+export async function suggestEngine(
+  binaryLocation: string,
+  name?: string
+): Promise<NewEngine> {
+  return await invoke<NewEngine>('suggest_engine', { binaryLocation, name })
 }
 
-export async function deleteEngineFromLichess(
-  engine: LichessEngine
-): Promise<void> {
-  const settings = useSettingsStore()
+export async function testEngine(path: string): Promise<EngineTest> {
+  return await invoke<EngineTest>('test_engine', { path })
+}
 
-  return await fetch(
-    `${settings.lichessHost}/api/external-engine/${engine.id}`,
-    {
-      method: 'DELETE',
-      headers: {
-        Authorization: `Bearer ${settings.lichess_token}`,
-      },
-    }
-  ).then(async (response) => {
-    if (!response.ok) {
-      throw await response.json()
-    }
-  })
+export async function getEngineLogs(engineId: string): Promise<string[]> {
+  return await invoke<string[]>('get_engine_logs', { engineId })
 }